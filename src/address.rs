@@ -0,0 +1,249 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Recipient addresses: rooms, or users to send a direct message to. Besides IDs and aliases,
+// matrix.to links and matrix: URIs are accepted, because that's what clients copy.
+
+use crate::state;
+use crate::trace;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::matrix_uri::MatrixId;
+use matrix_sdk::ruma::MatrixToUri;
+use matrix_sdk::ruma::MatrixUri;
+use matrix_sdk::ruma::OwnedRoomAliasId;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::OwnedRoomOrAliasId;
+use matrix_sdk::ruma::OwnedServerName;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::RoomAliasId;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use matrix_sdk::RoomState;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Clone, Debug)]
+pub enum Address {
+    // The servers to join the room through, from a link.
+    Room(OwnedRoomId, Vec<OwnedServerName>),
+    Alias(OwnedRoomAliasId),
+    User(OwnedUserId),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("https://matrix.to/") {
+            let uri = MatrixToUri::parse(s).map_err(|e| e.to_string())?;
+            return from_matrix_id(uri.id(), uri.via());
+        }
+        if s.starts_with("matrix:") {
+            let uri = MatrixUri::parse(s).map_err(|e| e.to_string())?;
+            return from_matrix_id(uri.id(), uri.via());
+        }
+        match s.chars().next() {
+            Some('!') => Ok(Address::Room(
+                OwnedRoomId::try_from(s).map_err(|e| e.to_string())?,
+                Vec::new(),
+            )),
+            Some('#') => Ok(Address::Alias(
+                OwnedRoomAliasId::try_from(s).map_err(|e| e.to_string())?,
+            )),
+            Some('@') => Ok(Address::User(
+                OwnedUserId::try_from(s).map_err(|e| e.to_string())?,
+            )),
+            _ => Err(String::from("not a room ID, room alias or user ID")),
+        }
+    }
+}
+
+fn from_room_or_alias(room: &OwnedRoomOrAliasId, via: &[OwnedServerName]) -> Address {
+    match OwnedRoomId::try_from(room.clone()) {
+        Ok(room_id) => Address::Room(room_id, via.to_vec()),
+        Err(alias) => Address::Alias(alias),
+    }
+}
+
+// A link to an event addresses its room.
+fn from_matrix_id(id: &MatrixId, via: &[OwnedServerName]) -> Result<Address, String> {
+    match id {
+        MatrixId::Room(room_id) => Ok(Address::Room(room_id.clone(), via.to_vec())),
+        MatrixId::RoomAlias(alias) => Ok(Address::Alias(alias.clone())),
+        MatrixId::User(user_id) => Ok(Address::User(user_id.clone())),
+        MatrixId::Event(room, _) => Ok(from_room_or_alias(room, via)),
+        _ => Err(String::from("not a link to a room or user")),
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Room(room_id, _) => write!(f, "{room_id}"),
+            Address::Alias(alias) => write!(f, "{alias}"),
+            Address::User(user_id) => write!(f, "{user_id}"),
+        }
+    }
+}
+
+// Whether the address is among the addresses, also when given differently, e.g. as link.
+pub fn contains(addresses: &[Address], address: &Address) -> bool {
+    let address = address.to_string();
+    addresses.iter().any(|other| other.to_string() == address)
+}
+
+fn joined_room(client: &Client, room_id: &OwnedRoomId) -> Option<Room> {
+    client
+        .get_room(room_id)
+        .filter(|room| room.state() == RoomState::Joined)
+}
+
+// The room IDs of aliases, to not ask the room directory every time.
+type Aliases = HashMap<OwnedRoomAliasId, OwnedRoomId>;
+
+// The homeserver answered that the address doesn't lead to a room: the alias or the user
+// doesn't exist, or the room can't be joined.
+#[derive(Debug)]
+pub struct Unresolvable {
+    address: String,
+    error: matrix_sdk::Error,
+}
+
+impl fmt::Display for Unresolvable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.address, self.error)
+    }
+}
+
+impl Error for Unresolvable {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// Refusals, but not rate limiting or an invalid session, which are worth trying again.
+fn unresolvable(address: impl fmt::Display, error: impl Into<matrix_sdk::Error>) -> Box<dyn Error> {
+    let error = error.into();
+    let refused = error
+        .as_client_api_error()
+        .is_some_and(|e| e.status_code.is_client_error());
+    match error.client_api_error_kind() {
+        Some(
+            ErrorKind::LimitExceeded { .. }
+            | ErrorKind::UnknownToken { .. }
+            | ErrorKind::MissingToken,
+        ) => error.into(),
+        _ if refused => Box::new(Unresolvable {
+            address: address.to_string(),
+            error,
+        }),
+        _ => error.into(),
+    }
+}
+
+async fn alias_room(
+    client: &Client,
+    alias: &RoomAliasId,
+    data_dir: &Path,
+) -> Result<Room, Box<dyn Error>> {
+    let file = data_dir.join("aliases");
+    let aliases: Aliases = state::read(&file).await?;
+    if let Some(room) = aliases
+        .get(alias)
+        .and_then(|room_id| joined_room(client, room_id))
+    {
+        return Ok(room);
+    }
+    // The alias may point to another room by now.
+    trace(format_args!("Resolving {alias}"));
+    let response = client
+        .resolve_room_alias(alias)
+        .await
+        .map_err(|e| unresolvable(alias, e))?;
+    state::update(&file, |aliases: &mut Aliases| {
+        aliases.insert(alias.to_owned(), response.room_id.clone())
+    })
+    .await?;
+    match joined_room(client, &response.room_id) {
+        Some(room) => Ok(room),
+        None => {
+            trace(format_args!("Joining {alias}"));
+            client
+                .join_room_by_id_or_alias(alias.into(), &response.servers)
+                .await
+                .map_err(|e| unresolvable(alias, e))
+        }
+    }
+}
+
+// The known room of the address, without joining or creating it, to look at it as it is.
+pub async fn find_room(client: &Client, address: &Address) -> Result<Option<Room>, Box<dyn Error>> {
+    match address {
+        Address::Room(room_id, _) => Ok(client.get_room(room_id)),
+        Address::Alias(alias) => {
+            let response = client
+                .resolve_room_alias(alias)
+                .await
+                .map_err(|e| unresolvable(alias, e))?;
+            Ok(client.get_room(&response.room_id))
+        }
+        Address::User(user_id) => Ok(client.get_dm_room(user_id)),
+    }
+}
+
+// The room to send to, joining it or creating a direct message room first if needed.
+pub async fn room(
+    client: &Client,
+    address: &Address,
+    data_dir: &Path,
+) -> Result<Room, Box<dyn Error>> {
+    match address {
+        Address::Room(room_id, via) => match joined_room(client, room_id) {
+            Some(room) => Ok(room),
+            None => {
+                trace(format_args!("Joining {room_id}"));
+                let joined = match via.is_empty() {
+                    true => client.join_room_by_id(room_id).await,
+                    false => {
+                        client
+                            .join_room_by_id_or_alias((&**room_id).into(), via)
+                            .await
+                    }
+                };
+                joined.map_err(|e| unresolvable(address, e))
+            }
+        },
+        Address::Alias(alias) => alias_room(client, alias, data_dir).await,
+        // The direct message room is created encrypted and added to m.direct.
+        Address::User(user_id) => match client.get_dm_room(user_id) {
+            Some(room) => Ok(room),
+            None => {
+                trace(format_args!(
+                    "Creating a direct message room with {user_id}"
+                ));
+                client
+                    .create_dm(user_id)
+                    .await
+                    .map_err(|e| unresolvable(address, e))
+            }
+        },
+    }
+}
@@ -0,0 +1,103 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// The address book in ~/.config/matrixmail/addressbook.toml, which names recipients and how
+// to send to them:
+//
+//   [alice]
+//   address = "@alice:example.org"
+//
+//   [alerts]
+//   address = "!jEsUZKDJdhlrceRyVU:example.org"
+//   notice = true
+//   require-encryption = true
+//
+//   [alerts.vars]
+//   team = "ops"
+
+use crate::address::Address;
+use crate::Args;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use tokio::fs;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Entry {
+    address: String,
+    // Send as notice, or as text even when notices are the default.
+    notice: Option<bool>,
+    #[serde(default)]
+    require_encryption: bool,
+    // Values for the {{vars.NAME}} placeholders of messages to the address.
+    #[serde(default)]
+    vars: HashMap<String, String>,
+}
+
+impl Entry {
+    pub fn vars(&self) -> &HashMap<String, String> {
+        &self.vars
+    }
+}
+
+pub type AddressBook = HashMap<String, Entry>;
+
+pub async fn load(file: &Path) -> Result<AddressBook, Box<dyn Error>> {
+    match fs::read_to_string(file).await {
+        Ok(text) => Ok(toml::from_str(&text).map_err(|e| format!("{}: {e}", file.display()))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AddressBook::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The address of the name, if it is in the address book.
+pub fn lookup<'a>(book: &'a AddressBook, name: &str) -> Option<&'a str> {
+    book.get(name).map(|entry| entry.address.as_str())
+}
+
+// The name and entry of the address. This holds for the address itself too, not only for its
+// name.
+pub fn entry<'a>(book: &'a AddressBook, address: &Address) -> Option<(&'a str, &'a Entry)> {
+    let address = address.to_string();
+    book.iter()
+        .find(|(_, entry)| {
+            entry
+                .address
+                .parse::<Address>()
+                .is_ok_and(|entry_address| entry_address.to_string() == address)
+        })
+        .map(|(name, entry)| (name.as_str(), entry))
+}
+
+// The arguments for sending to the address, if its entry changes them.
+pub fn args(book: &AddressBook, address: &Address, args: &Args) -> Option<Args> {
+    let (_, entry) = entry(book, address)?;
+    if entry.notice.is_none() && !entry.require_encryption {
+        return None;
+    }
+    let mut args = args.clone();
+    if let Some(notice) = entry.notice {
+        args.notice = notice;
+        args.emote = false;
+        args.msgtype = None;
+    }
+    args.require_encryption |= entry.require_encryption;
+    Some(args)
+}
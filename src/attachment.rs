@@ -0,0 +1,207 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Files attached with -a, sent as separate events after the message.
+
+use crate::media;
+use crate::rate_limited;
+use crate::trace;
+use matrix_sdk::attachment::AttachmentConfig;
+use matrix_sdk::attachment::AttachmentInfo;
+use matrix_sdk::attachment::BaseAudioInfo;
+use matrix_sdk::attachment::BaseFileInfo;
+use matrix_sdk::attachment::BaseImageInfo;
+use matrix_sdk::attachment::BaseVideoInfo;
+use matrix_sdk::ruma::events::room::ImageInfo;
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::OwnedMxcUri;
+use matrix_sdk::ruma::TransactionId;
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use mime_guess::Mime;
+use std::error::Error;
+use std::path::Path;
+use tokio::fs;
+use tokio::sync::OnceCell;
+
+pub struct Attachment {
+    name: String,
+    content_type: Mime,
+    data: Vec<u8>,
+}
+
+impl Attachment {
+    pub fn new(name: String, content_type: Mime, data: Vec<u8>) -> Attachment {
+        Attachment {
+            name,
+            content_type,
+            data,
+        }
+    }
+}
+
+// Read the file before sending anything, so that a missing file doesn't leave half a mail.
+pub async fn load(path: &Path) -> Result<Attachment, Box<dyn Error>> {
+    let data = fs::read(path)
+        .await
+        .map_err(|e| format!("Error reading {}: {e}", path.display()))?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or(String::from("attachment"));
+    // The content tells the type better than the file name.
+    let content_type = match infer::get(&data) {
+        Some(kind) => kind.mime_type().parse()?,
+        None => mime_guess::from_path(path).first_or_octet_stream(),
+    };
+    Ok(Attachment {
+        name,
+        content_type,
+        data,
+    })
+}
+
+// Size and, for images, dimensions, so that clients can show a preview of the right size.
+fn info(attachment: &Attachment) -> AttachmentInfo {
+    let size = UInt::new(attachment.data.len() as u64);
+    let dimensions = imagesize::blob_size(&attachment.data).ok();
+    let width = dimensions.and_then(|dimensions| UInt::new(dimensions.width as u64));
+    let height = dimensions.and_then(|dimensions| UInt::new(dimensions.height as u64));
+    match attachment.content_type.type_() {
+        mime_guess::mime::IMAGE => AttachmentInfo::Image(BaseImageInfo {
+            height,
+            width,
+            size,
+            blurhash: None,
+        }),
+        mime_guess::mime::VIDEO => AttachmentInfo::Video(BaseVideoInfo {
+            duration: None,
+            height: None,
+            width: None,
+            size,
+            blurhash: None,
+        }),
+        mime_guess::mime::AUDIO => AttachmentInfo::Audio(BaseAudioInfo {
+            duration: None,
+            size,
+        }),
+        _ => AttachmentInfo::File(BaseFileInfo { size }),
+    }
+}
+
+// The same for images that are referred to by their URI, like stickers.
+pub fn image_info(attachment: &Attachment) -> ImageInfo {
+    let dimensions = imagesize::blob_size(&attachment.data).ok();
+    let mut info = ImageInfo::new();
+    info.width = dimensions.and_then(|dimensions| UInt::new(dimensions.width as u64));
+    info.height = dimensions.and_then(|dimensions| UInt::new(dimensions.height as u64));
+    info.mimetype = Some(attachment.content_type.to_string());
+    info.size = UInt::new(attachment.data.len() as u64);
+    info
+}
+
+// The event type (m.image, m.video, m.audio or m.file) follows from the MIME type.
+// In encrypted rooms the file is encrypted before uploading.
+pub async fn send(
+    room: &Room,
+    attachment: &Attachment,
+    txn_id: &TransactionId,
+) -> Result<OwnedEventId, Box<dyn Error>> {
+    let response = room
+        .send_attachment(
+            &attachment.name,
+            &attachment.content_type,
+            attachment.data.clone(),
+            AttachmentConfig::new()
+                .txn_id(txn_id)
+                .info(info(attachment)),
+        )
+        .await?;
+    Ok(response.event_id)
+}
+
+// Upload the file on its own, to refer to it from a message. Unlike attachments, the upload
+// isn't encrypted, because clients only show unencrypted images in formatted messages.
+pub async fn upload(
+    client: &Client,
+    attachment: &Attachment,
+) -> Result<OwnedMxcUri, Box<dyn Error>> {
+    let response = client
+        .media()
+        .upload(&attachment.content_type, attachment.data.clone())
+        .await?;
+    Ok(response.content_uri)
+}
+
+// The same for an event in the room, encrypted for encrypted rooms.
+pub async fn upload_source(
+    client: &Client,
+    attachment: &Attachment,
+    encrypted: bool,
+) -> Result<MediaSource, Box<dyn Error>> {
+    media::upload(
+        client,
+        &attachment.content_type,
+        &attachment.data,
+        encrypted,
+    )
+    .await
+}
+
+// The inline images of HTML mail, by content ID. They are uploaded once, when first sent to an
+// unencrypted room; encrypted rooms get them as attachments instead.
+pub struct Inline {
+    images: Vec<(String, Attachment)>,
+    uris: OnceCell<Vec<(String, OwnedMxcUri)>>,
+}
+
+impl Inline {
+    pub fn new(images: Vec<(String, Attachment)>) -> Inline {
+        Inline {
+            images,
+            uris: OnceCell::new(),
+        }
+    }
+
+    pub fn images(&self) -> impl Iterator<Item = &Attachment> {
+        self.images.iter().map(|(_, image)| image)
+    }
+
+    // The HTML showing the images from the media repository.
+    pub async fn html(&self, client: &Client, html: &str) -> Result<String, Box<dyn Error>> {
+        let uris = self
+            .uris
+            .get_or_try_init(|| async {
+                let mut uris = Vec::new();
+                for (content_id, image) in &self.images {
+                    trace(format_args!("Uploading the inline image {content_id}"));
+                    let uri = rate_limited(|| upload(client, image)).await?;
+                    uris.push((format!("cid:{content_id}"), uri));
+                }
+                Ok::<_, Box<dyn Error>>(uris)
+            })
+            .await?;
+        let mut html = String::from(html);
+        for (cid, uri) in uris {
+            html = html.replace(cid, uri.as_str());
+        }
+        Ok(html)
+    }
+}
@@ -0,0 +1,134 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Shell completion: the options of both commands from clap, and for send mode the recipients,
+// which the scripts get from `mail --complete-addresses`: the aliases, the address book and the
+// rooms in the local store, without asking the homeserver.
+
+use crate::addressbook::AddressBook;
+use crate::mailrc::Mailrc;
+use crate::Args;
+use crate::Cli;
+use crate::Session;
+use clap::CommandFactory;
+use clap_complete::Shell;
+use matrix_sdk::matrix_auth::MatrixSession;
+use matrix_sdk::matrix_auth::MatrixSessionTokens;
+use matrix_sdk::Client;
+use matrix_sdk::SessionMeta;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use tokio::fs;
+
+const BASH: &str = r#"
+_mail_matrixmail() {
+    _mail "$@"
+    local cur prev
+    if declare -F _get_comp_words_by_ref >/dev/null; then
+        _get_comp_words_by_ref -n : cur prev
+    else
+        cur="${COMP_WORDS[COMP_CWORD]}"
+        prev="${COMP_WORDS[COMP_CWORD-1]}"
+    fi
+    if [[ "$prev" == -[cb] || ( ${#COMPREPLY[@]} -eq 0 && "$cur" != -* ) ]]; then
+        local IFS=$'\n'
+        COMPREPLY=($(compgen -W "$(mail --complete-addresses 2>/dev/null)" -- "$cur"))
+        if declare -F __ltrim_colon_completions >/dev/null; then
+            __ltrim_colon_completions "$cur"
+        fi
+    fi
+}
+complete -F _mail_matrixmail -o bashdefault -o default mail mailx
+"#;
+
+const ZSH: &str = r#"
+_mail_matrixmail() {
+    local -a addresses
+    addresses=(${(f)"$(mail --complete-addresses 2>/dev/null)"})
+    _alternative 'options:option:_mail' 'addresses:address:compadd -a addresses'
+}
+compdef _mail_matrixmail mail mailx
+"#;
+
+const FISH: &str = r#"
+complete -c mail -f -a '(mail --complete-addresses 2>/dev/null)'
+complete -c mailx -w mail
+"#;
+
+// The script for matrixmail's commands and for send mode as mail and mailx.
+pub fn script(shell: Shell) -> Result<(), Box<dyn Error>> {
+    let hook = match shell {
+        Shell::Bash => BASH,
+        Shell::Zsh => ZSH,
+        Shell::Fish => FISH,
+        _ => return Err(format!("No completion for {shell}").into()),
+    };
+    let mut stdout = io::stdout();
+    clap_complete::generate(shell, &mut Cli::command(), "matrixmail", &mut stdout);
+    clap_complete::generate(shell, &mut Args::command(), "mail", &mut stdout);
+    println!("{hook}");
+    Ok(())
+}
+
+// The store of the session, without its tokens, which the keyring may guard. Completing must not
+// prompt, so an encrypted session file is left alone.
+async fn stored_client(data_dir: &Path, session_file: &Path) -> Result<Client, Box<dyn Error>> {
+    let data = fs::read(session_file).await?;
+    if data.starts_with(b"age-encryption.org/") {
+        return Err("The session file is encrypted".into());
+    }
+    let session: Session = serde_json::from_slice(&data)?;
+    let client = Client::builder()
+        .homeserver_url(&session.homeserver)
+        .sqlite_store(data_dir, None)
+        .build()
+        .await?;
+    client
+        .restore_session(MatrixSession {
+            meta: SessionMeta {
+                user_id: session.user_id,
+                device_id: session.device_id,
+            },
+            tokens: MatrixSessionTokens {
+                access_token: String::new(),
+                refresh_token: None,
+            },
+        })
+        .await?;
+    Ok(client)
+}
+
+// The names to complete recipients with, one per line. Without a session, or with an encrypted
+// one, only the aliases and the address book.
+pub async fn addresses(data_dir: &Path, session_file: &Path, mailrc: &Mailrc, book: &AddressBook) {
+    let mut names: BTreeSet<String> = mailrc.aliases().map(String::from).collect();
+    names.extend(book.keys().cloned());
+    if let Ok(client) = stored_client(data_dir, session_file).await {
+        for room in client.joined_rooms() {
+            names.extend(room.canonical_alias().map(|alias| alias.to_string()));
+            names.extend(room.alt_aliases().iter().map(ToString::to_string));
+            // Direct message rooms are addressed by the other user.
+            names.extend(room.direct_targets().iter().map(ToString::to_string));
+        }
+    }
+    for name in names {
+        println!("{name}");
+    }
+}
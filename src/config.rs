@@ -0,0 +1,191 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Defaults from ~/.config/matrixmail/config.toml, for what would otherwise need the same
+// options on every run. Sections like [profile.work] override them for --profile work.
+//
+//   homeserver = "example.org"
+//   markdown = true
+//   sent-room = "#sent:example.org"
+//
+//   [aliases]
+//   ops = "#ops:example.org"
+//   oncall = ["@alice:example.org", "@bob:example.org"]
+//
+//   [profile.work]
+//   homeserver = "matrix.example.com"
+//   msgtype = "m.notice"
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tokio::fs;
+
+// Not deny_unknown_fields, which serde ignores when flattened; Section reports them instead.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Settings {
+    // The default at login and registration.
+    pub homeserver: Option<String>,
+    pub msgtype: Option<String>,
+    pub markdown: Option<bool>,
+    // How long a sync waits for new events in seconds, when syncing continuously.
+    pub sync_timeout: Option<u64>,
+    // How often a rate limited request is sent before giving up.
+    pub retry_attempts: Option<u32>,
+    // An HTTP, HTTPS or SOCKS5 proxy URL for reaching the homeserver.
+    pub proxy: Option<String>,
+    // PEM files with certificates to trust besides the system's, or instead of them.
+    pub ca_file: Option<PathBuf>,
+    pub pin_cert: Option<PathBuf>,
+    // Connect through Tor, e.g. to an onion service homeserver.
+    pub tor: Option<bool>,
+    // Where the session and store are kept.
+    pub data_dir: Option<PathBuf>,
+    pub sent_room: Option<String>,
+    pub record: Option<PathBuf>,
+    // Names for recipients, like the aliases in ~/.mailrc.
+    #[serde(default)]
+    pub aliases: HashMap<String, Addresses>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Addresses {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Addresses {
+    pub fn to_vec(&self) -> Vec<String> {
+        match self {
+            Addresses::One(address) => vec![address.clone()],
+            Addresses::Many(addresses) => addresses.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct Section {
+    #[serde(flatten)]
+    settings: Settings,
+    // The keys left over, misspelled ones for example.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize, Default)]
+struct Config {
+    #[serde(default)]
+    profile: HashMap<String, Section>,
+    #[serde(flatten)]
+    section: Section,
+}
+
+fn parse(text: &str) -> Result<Config, String> {
+    let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+    let mut unknown: Vec<String> = config.section.unknown.keys().cloned().collect();
+    for (name, section) in &config.profile {
+        unknown.extend(
+            section
+                .unknown
+                .keys()
+                .map(|key| format!("profile.{name}.{key}")),
+        );
+    }
+    match unknown.is_empty() {
+        true => Ok(config),
+        false => Err(format!("unknown keys: {}", unknown.join(", "))),
+    }
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+// The settings of the profile, falling back to the ones outside of profile sections.
+fn merge(config: Config, profile: Option<&str>) -> Settings {
+    let defaults = config.section.settings;
+    let mut profiles = config.profile;
+    let settings = profile
+        .and_then(|profile| profiles.remove(profile))
+        .map(|section| section.settings)
+        .unwrap_or_default();
+    // Like without configuration, the profiles' data directories are inside the shared one,
+    // unless they have their own.
+    let data_dir = match (settings.data_dir, defaults.data_dir, profile) {
+        (Some(dir), _, _) => Some(dir),
+        (None, Some(dir), Some(profile)) => Some(dir.join(profile)),
+        (None, dir, None) => dir,
+        (None, None, Some(_)) => None,
+    };
+    let mut aliases = defaults.aliases;
+    aliases.extend(settings.aliases);
+    Settings {
+        homeserver: settings.homeserver.or(defaults.homeserver),
+        msgtype: settings.msgtype.or(defaults.msgtype),
+        markdown: settings.markdown.or(defaults.markdown),
+        sync_timeout: settings.sync_timeout.or(defaults.sync_timeout),
+        retry_attempts: settings.retry_attempts.or(defaults.retry_attempts),
+        proxy: settings.proxy.or(defaults.proxy),
+        ca_file: settings.ca_file.or(defaults.ca_file),
+        pin_cert: settings.pin_cert.or(defaults.pin_cert),
+        tor: settings.tor.or(defaults.tor),
+        data_dir,
+        sent_room: settings.sent_room.or(defaults.sent_room),
+        record: settings.record.or(defaults.record),
+        aliases,
+    }
+}
+
+// Load the settings once, before anything uses them. Without the file, there are none.
+pub async fn load(file: &Path, profile: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let config = match fs::read_to_string(file).await {
+        Ok(text) => parse(&text).map_err(|e| format!("{}: {e}", file.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+        Err(e) => return Err(e.into()),
+    };
+    let _ = SETTINGS.set(merge(config, profile));
+    Ok(())
+}
+
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misspelled_key() {
+        assert!(parse("markdown = true").is_ok());
+        let error = parse("markdwon = true").err().unwrap();
+        assert_eq!(error, "unknown keys: markdwon");
+    }
+
+    #[test]
+    fn misspelled_profile_key() {
+        let error = parse("[profile.work]\nhomeservr = \"example.org\"")
+            .err()
+            .unwrap();
+        assert_eq!(error, "unknown keys: profile.work.homeservr");
+    }
+}
@@ -0,0 +1,92 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// --confirm: before sending, show on the terminal which rooms get the message, whether they are
+// encrypted and how it starts, and ask whether to send it, e.g. when broadcasting to many rooms.
+
+use crate::address;
+use crate::address::Address;
+use matrix_sdk::Client;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+
+// How much of the message to show.
+const PREVIEW_LINES: usize = 5;
+const PREVIEW_WIDTH: usize = 76;
+
+async fn describe(client: &Client, address: &Address) -> Result<String, Box<dyn Error>> {
+    let Some(room) = address::find_room(client, address).await? else {
+        return Ok(match address {
+            Address::User(_) => String::from("new direct message room, encrypted"),
+            _ => String::from("not joined yet"),
+        });
+    };
+    let encryption = match room.is_encrypted().await? {
+        true => "encrypted",
+        false => "NOT encrypted",
+    };
+    Ok(format!("{}, {encryption}", room.display_name().await?))
+}
+
+// Whether to send. Without a terminal there is no one to ask, so the message is sent.
+pub async fn confirm(
+    client: &Client,
+    addresses: &[&Address],
+    message: &str,
+    attachments: usize,
+) -> Result<bool, Box<dyn Error>> {
+    let Ok(mut tty) = OpenOptions::new().read(true).write(true).open("/dev/tty") else {
+        return Ok(true);
+    };
+    for address in addresses {
+        let description = match describe(client, address).await {
+            Ok(description) => description,
+            Err(e) => format!("unknown ({e})"),
+        };
+        writeln!(tty, "To: {address} ({description})")?;
+    }
+    writeln!(tty)?;
+    let lines: Vec<&str> = message.lines().collect();
+    for line in lines.iter().take(PREVIEW_LINES) {
+        let preview: String = line.chars().take(PREVIEW_WIDTH).collect();
+        writeln!(tty, "  {preview}")?;
+    }
+    if lines.len() > PREVIEW_LINES {
+        writeln!(tty, "  [{} more lines]", lines.len() - PREVIEW_LINES)?;
+    }
+    if attachments > 0 {
+        writeln!(tty, "  [{attachments} attachments]")?;
+    }
+    loop {
+        write!(tty, "Send? [y/n] ")?;
+        tty.flush()?;
+        let mut answer = String::new();
+        // End of input is no.
+        if BufReader::new(&tty).read_line(&mut answer)? == 0 {
+            return Ok(false);
+        }
+        match answer.trim() {
+            "y" | "Y" | "yes" => return Ok(true),
+            "n" | "N" | "no" => return Ok(false),
+            _ => {}
+        }
+    }
+}
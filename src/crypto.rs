@@ -0,0 +1,175 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Cross-signing, so that recipients see messages from a verified device, and the keys kept
+// in secret storage on the homeserver.
+
+use crate::getpass;
+use crate::password_auth;
+use futures_util::FutureExt;
+use futures_util::StreamExt;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::ruma::api::client::filter::FilterDefinition;
+use matrix_sdk::ruma::events::room_key_request::Action;
+use matrix_sdk::ruma::events::room_key_request::ToDeviceRoomKeyRequestEvent;
+use matrix_sdk::Client;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::Path;
+use std::pin::pin;
+use std::time::Duration;
+
+// Fetch the account's keys, which the crypto store only has after a sync.
+async fn sync_keys(client: &Client) -> Result<(), Box<dyn Error>> {
+    let filter = FilterDefinition::with_lazy_loading();
+    client
+        .sync_once(SyncSettings::default().filter(filter.into()))
+        .await?;
+    Ok(())
+}
+
+async fn sign_device(client: &Client) -> Result<(), Box<dyn Error>> {
+    let device = client.encryption().get_own_device().await?.unwrap();
+    device.verify().await?;
+    Ok(())
+}
+
+// Create and upload cross-signing keys and sign this device with them.
+// Replacing existing keys makes all devices of the account unverified, so it needs reset.
+pub async fn bootstrap(client: &Client, reset: bool) -> Result<(), Box<dyn Error>> {
+    sync_keys(client).await?;
+    let encryption = client.encryption();
+    let user_id = client.user_id().unwrap();
+    let status = encryption.cross_signing_status().await;
+    if status.as_ref().is_some_and(|status| status.is_complete()) {
+        // The private keys are already here, only the signature might be missing.
+        sign_device(client).await?;
+        println!("Device signed.");
+        return Ok(());
+    }
+    if !reset && encryption.get_user_identity(user_id).await?.is_some() {
+        return Err(
+            "The account already has cross-signing keys, verify this device instead or use --reset"
+                .into(),
+        );
+    }
+    // The first request only tells which authentication is needed.
+    if let Err(e) = encryption.bootstrap_cross_signing(None).await {
+        let Some(info) = e.as_uiaa_response() else {
+            return Err(e.into());
+        };
+        let auth = password_auth(client, info)?;
+        encryption.bootstrap_cross_signing(Some(auth)).await?;
+    }
+    println!("Cross-signing keys created, device signed.");
+    Ok(())
+}
+
+// Open secret storage and import the secrets in it. When there is a backup key, this also
+// enables the key backup, but room keys are only downloaded from it when asked for.
+async fn recover(client: &Client) -> Result<(), Box<dyn Error>> {
+    sync_keys(client).await?;
+    let key = getpass("Recovery key or passphrase: ")?;
+    client.encryption().recovery().recover(key.trim()).await?;
+    Ok(())
+}
+
+// Import the cross-signing and backup keys from secret storage, making this a verified device
+// that can read the backed up room keys.
+pub async fn unlock(client: &Client) -> Result<(), Box<dyn Error>> {
+    recover(client).await?;
+    let status = client.encryption().cross_signing_status().await;
+    if !status.is_some_and(|status| status.is_complete()) {
+        return Err("Secret storage has no cross-signing keys".into());
+    }
+    sign_device(client).await?;
+    println!("Secrets imported, device signed.");
+    Ok(())
+}
+
+// Restore the room keys from the server-side key backup, to decrypt messages sent before login.
+pub async fn restore_backup(client: &Client) -> Result<(), Box<dyn Error>> {
+    recover(client).await?;
+    let backups = client.encryption().backups();
+    if !backups.are_enabled().await {
+        return Err("The account has no key backup".into());
+    }
+    let mut count = 0;
+    for room in client.joined_rooms() {
+        // Subscribed before downloading, the stream has the session IDs of the imported keys.
+        let mut imported = pin!(backups.room_keys_for_room_stream(room.room_id()));
+        backups.download_room_keys_for_room(room.room_id()).await?;
+        while let Some(Some(keys)) = imported.next().now_or_never() {
+            count += keys?.values().map(BTreeSet::len).sum::<usize>();
+        }
+    }
+    println!("{count} room keys restored.");
+    Ok(())
+}
+
+// Export all room keys to a passphrase-protected file, in the format Element uses.
+pub async fn export_keys(client: &Client, file: &Path) -> Result<(), Box<dyn Error>> {
+    let passphrase = getpass("Passphrase: ")?;
+    if getpass("Repeat passphrase: ")? != passphrase {
+        return Err("Passphrases don't match".into());
+    }
+    client
+        .encryption()
+        .export_room_keys(file.to_path_buf(), &passphrase, |_| true)
+        .await?;
+    Ok(())
+}
+
+pub async fn import_keys(client: &Client, file: &Path) -> Result<(), Box<dyn Error>> {
+    let passphrase = getpass("Passphrase: ")?;
+    let result = client
+        .encryption()
+        .import_room_keys(file.to_path_buf(), &passphrase)
+        .await?;
+    println!(
+        "Imported {} of {} room keys.",
+        result.imported_count, result.total_count
+    );
+    Ok(())
+}
+
+// Answer room key requests from the other devices of the account for a while, so that they can
+// read what matrixmail sent. The SDK answers verified devices on its own while syncing, with
+// automatic-room-key-forwarding; the requests are printed to see that they arrive.
+pub async fn serve_keys(client: &Client, duration: Duration) -> Result<(), Box<dyn Error>> {
+    let filter = FilterDefinition::with_lazy_loading();
+    let settings = SyncSettings::default().filter(filter.into());
+    client.add_event_handler(|event: ToDeviceRoomKeyRequestEvent| async move {
+        if event.content.action != Action::Request {
+            return;
+        }
+        let session_id = match &event.content.body {
+            Some(body) => body.session_id.as_str(),
+            None => "",
+        };
+        println!(
+            "Key request from {} {} for session {session_id}",
+            event.sender, event.content.requesting_device_id
+        );
+    });
+    println!("Answering key requests for {} seconds.", duration.as_secs());
+    if let Ok(result) = tokio::time::timeout(duration, client.sync(settings)).await {
+        result?;
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Device management, mostly to clean up after repeated logins.
+
+use crate::password_auth;
+use crate::time;
+use matrix_sdk::ruma::DeviceId;
+use matrix_sdk::ruma::OwnedDeviceId;
+use matrix_sdk::Client;
+use std::error::Error;
+
+pub async fn list(client: &Client) -> Result<(), Box<dyn Error>> {
+    let own_device_id = client.device_id();
+    let mut devices = client.devices().await?.devices;
+    devices.sort_by_key(|device| device.last_seen_ts);
+    for device in devices {
+        let last_seen = match device.last_seen_ts {
+            Some(ts) => time::format_local(u64::from(ts.as_secs()) as i64, "%Y-%m-%d %H:%M"),
+            None => String::from("-"),
+        };
+        let this_device = match own_device_id == Some(&device.device_id) {
+            true => " (this device)",
+            false => "",
+        };
+        println!(
+            "{}\t{}\t{}\t{}{}",
+            device.device_id,
+            last_seen,
+            device.last_seen_ip.as_deref().unwrap_or("-"),
+            device.display_name.as_deref().unwrap_or(""),
+            this_device,
+        );
+    }
+    Ok(())
+}
+
+pub async fn rename(
+    client: &Client,
+    device_id: &DeviceId,
+    display_name: &str,
+) -> Result<(), Box<dyn Error>> {
+    client.rename_device(device_id, display_name).await?;
+    Ok(())
+}
+
+pub async fn delete(client: &Client, device_ids: &[OwnedDeviceId]) -> Result<(), Box<dyn Error>> {
+    if device_ids
+        .iter()
+        .any(|device_id| client.device_id() == Some(device_id))
+    {
+        return Err("Refusing to delete this device, use logout instead".into());
+    }
+    // The first request only tells which authentication is needed.
+    if let Err(e) = client.delete_devices(device_ids, None).await {
+        let Some(info) = e.as_uiaa_response() else {
+            return Err(e.into());
+        };
+        let auth = password_auth(client, info)?;
+        client.delete_devices(device_ids, Some(auth)).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Messages sent with --expire, redacted by `reap` once their time is up, e.g. one-time
+// passwords. The events are kept in a file in the data directory until then.
+
+use crate::queue;
+use crate::state;
+use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::Client;
+use serde::Deserialize;
+use serde::Serialize;
+use std::error::Error;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize)]
+struct Expiring {
+    // Seconds since the epoch.
+    due: i64,
+    room_id: OwnedRoomId,
+    event_id: OwnedEventId,
+}
+
+pub async fn record(
+    data_dir: &Path,
+    due: i64,
+    sent: &[(OwnedRoomId, Vec<OwnedEventId>)],
+) -> Result<(), Box<dyn Error>> {
+    let file = data_dir.join("expiring");
+    state::update(&file, |expiring: &mut Vec<Expiring>| {
+        for (room_id, event_ids) in sent {
+            expiring.extend(event_ids.iter().map(|event_id| Expiring {
+                due,
+                room_id: room_id.clone(),
+                event_id: event_id.clone(),
+            }));
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+// Redact the messages that are due. Those that fail stay for the next time.
+pub async fn reap(client: &Client, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let file = data_dir.join("expiring");
+    let mut redacted = Vec::new();
+    let mut failed = 0;
+    let all: Vec<Expiring> = state::read(&file).await?;
+    for expiring in all {
+        if expiring.due > queue::now() {
+            continue;
+        }
+        let result = match client.get_room(&expiring.room_id) {
+            Some(room) => room
+                .redact(&expiring.event_id, Some("Expired"), None)
+                .await
+                .map_err(|e| e.to_string()),
+            None => Err(String::from("not joined")),
+        };
+        if let Err(e) = result {
+            eprintln!(
+                "Error redacting {} in {}: {e}",
+                expiring.event_id, expiring.room_id
+            );
+            failed += 1;
+            continue;
+        }
+        redacted.push(expiring.event_id);
+    }
+    // Messages may have been sent with --expire in the meantime.
+    state::update(&file, |expiring: &mut Vec<Expiring>| {
+        expiring.retain(|expiring| !redacted.contains(&expiring.event_id))
+    })
+    .await?;
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!("{failed} messages could not be redacted").into()),
+    }
+}
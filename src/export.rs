@@ -0,0 +1,248 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Room history as mail, one message per event, to archive rooms into tools that read mbox, or
+// as the events themselves in JSON, for jq. Files, images, audio and video are downloaded (and
+// decrypted) into a MIME part, and with --download-media also saved as files.
+
+use crate::record;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::ValueEnum;
+use matrix_sdk::media::MediaFormat;
+use matrix_sdk::media::MediaRequest;
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::OriginalRoomMessageEvent;
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::AnyMessageLikeEvent;
+use matrix_sdk::ruma::events::AnyTimelineEvent;
+use matrix_sdk::ruma::events::MessageLikeEvent;
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use std::error::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use tokio::fs;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// One file with all messages (mboxrd)
+    #[default]
+    Mbox,
+    /// A JSON object per event, of all kinds (NDJSON)
+    Json,
+}
+
+// How many events to ask for at once.
+const PAGE_SIZE: u32 = 100;
+
+// The file of a message, with its name and MIME type.
+pub fn media(message: &OriginalRoomMessageEvent) -> Option<(MediaSource, String, String)> {
+    let (source, name, mimetype) = match &message.content.msgtype {
+        MessageType::File(file) => (
+            &file.source,
+            &file.body,
+            file.info.as_ref().and_then(|info| info.mimetype.clone()),
+        ),
+        MessageType::Image(image) => (
+            &image.source,
+            &image.body,
+            image.info.as_ref().and_then(|info| info.mimetype.clone()),
+        ),
+        MessageType::Audio(audio) => (
+            &audio.source,
+            &audio.body,
+            audio.info.as_ref().and_then(|info| info.mimetype.clone()),
+        ),
+        MessageType::Video(video) => (
+            &video.source,
+            &video.body,
+            video.info.as_ref().and_then(|info| info.mimetype.clone()),
+        ),
+        _ => return None,
+    };
+    let mimetype = mimetype.unwrap_or(String::from("application/octet-stream"));
+    Some((source.clone(), name.clone(), mimetype))
+}
+
+// The content of the file, decrypted if the room is encrypted.
+pub async fn fetch(client: &Client, source: MediaSource) -> Result<Vec<u8>, matrix_sdk::Error> {
+    let request = MediaRequest {
+        source,
+        format: MediaFormat::File,
+    };
+    client.media().get_media_content(&request, true).await
+}
+
+// Where to save the file in the directory, after the time of the message so that files with
+// the same name don't overwrite each other.
+fn media_file(dir: &Path, time: i64, name: &str) -> PathBuf {
+    dir.join(format!("{time}-{}", name.replace('/', "_")))
+}
+
+// A user ID like @alice:example.org as address alice@example.org, which mail tools understand.
+fn address(user_id: &str) -> String {
+    let (localpart, server_name) = user_id[1..].split_once(':').unwrap_or((&user_id[1..], ""));
+    format!("{localpart}@{server_name}")
+}
+
+// The message as mail: the sender's address, the time and the RFC 5322 text.
+pub async fn mail(
+    client: &Client,
+    room_name: &str,
+    message: &OriginalRoomMessageEvent,
+) -> (String, i64, String) {
+    let sender = address(message.sender.as_str());
+    let body = message.content.body();
+    let subject: String = body
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .take(78)
+        .collect();
+    let mut headers = vec![
+        ("From", format!("\"{}\" <{sender}>", message.sender)),
+        (
+            "To",
+            format!("\"{room_name}\" <{}>", address(message.room_id.as_str())),
+        ),
+        ("Subject", subject),
+        (
+            "Message-ID",
+            format!(
+                "<{}@{}>",
+                &message.event_id.as_str()[1..],
+                message.sender.server_name()
+            ),
+        ),
+        ("MIME-Version", String::from("1.0")),
+    ];
+    let time = u64::from(message.origin_server_ts.as_secs()) as i64;
+    let data = match media(message) {
+        Some((source, name, mimetype)) => {
+            match fetch(client, source).await {
+                Ok(data) => Some((name, mimetype, data)),
+                // The message is still worth keeping.
+                Err(e) => {
+                    eprintln!("Error downloading {name} of {}: {e}", message.event_id);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let Some((name, mimetype, data)) = data else {
+        headers.push(("Content-Type", String::from("text/plain; charset=utf-8")));
+        headers.push(("Content-Transfer-Encoding", String::from("8bit")));
+        return (sender, time, record::message(time, &headers, body));
+    };
+    let boundary = format!("matrixmail-{}", &message.event_id.as_str()[1..]);
+    headers.push((
+        "Content-Type",
+        format!("multipart/mixed; boundary=\"{boundary}\""),
+    ));
+    let name = name.replace('"', "");
+    let mut mime_body = format!("--{boundary}\n");
+    mime_body.push_str("Content-Type: text/plain; charset=utf-8\n");
+    mime_body.push_str("Content-Transfer-Encoding: 8bit\n\n");
+    mime_body.push_str(&format!("{body}\n--{boundary}\n"));
+    mime_body.push_str(&format!("Content-Type: {mimetype}; name=\"{name}\"\n"));
+    mime_body.push_str(&format!(
+        "Content-Disposition: attachment; filename=\"{name}\"\n"
+    ));
+    mime_body.push_str("Content-Transfer-Encoding: base64\n\n");
+    let encoded = STANDARD.encode(data);
+    for line in encoded.as_bytes().chunks(76) {
+        mime_body.push_str(&String::from_utf8_lossy(line));
+        mime_body.push('\n');
+    }
+    mime_body.push_str(&format!("--{boundary}--"));
+    let text = record::message(time, &headers, &mime_body);
+    (sender, time, text)
+}
+
+// Page back through the room's messages up to the time, then write them oldest first.
+pub async fn export(
+    client: &Client,
+    room: &Room,
+    since: Option<i64>,
+    format: Format,
+    file: &Path,
+    media_dir: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let room_name = room.display_name().await?.to_string();
+    let mut entries = Vec::new();
+    let mut options = MessagesOptions::backward();
+    'pages: loop {
+        options.limit = UInt::from(PAGE_SIZE);
+        let messages = room.messages(options).await?;
+        for timeline_event in messages.chunk {
+            let Ok(event) = timeline_event.event.deserialize() else {
+                continue;
+            };
+            let time = u64::from(event.origin_server_ts().as_secs()) as i64;
+            if since.is_some_and(|since| time < since) {
+                break 'pages;
+            }
+            // Encrypted events are decrypted already, re-serialized to be on a single line.
+            if format == Format::Json {
+                let value: serde_json::Value = timeline_event.event.deserialize_as()?;
+                entries.push(format!("{value}\n"));
+            }
+            let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+                MessageLikeEvent::Original(message),
+            )) = event
+            else {
+                continue;
+            };
+            if format == Format::Mbox {
+                let (sender, time, text) = mail(client, &room_name, &message).await;
+                entries.push(record::entry(&sender, time, &text));
+            }
+            // From the media cache for mbox, it was just downloaded for the mail.
+            if let (Some(dir), Some((source, name, _))) = (media_dir, media(&message)) {
+                let result = match fetch(client, source).await {
+                    Ok(data) => fs::write(media_file(dir, time, &name), data)
+                        .await
+                        .map_err(Box::<dyn Error>::from),
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    eprintln!("Error saving {name} of {}: {e}", message.event_id);
+                }
+            }
+        }
+        // The start of the room.
+        let Some(end) = messages.end else {
+            break;
+        };
+        options = MessagesOptions::backward();
+        options.from = Some(end);
+    }
+    entries.reverse();
+    fs::write(file, entries.concat()).await?;
+    let kind = match format {
+        Format::Mbox => "messages",
+        Format::Json => "events",
+    };
+    println!("Exported {} {kind}.", entries.len());
+    Ok(())
+}
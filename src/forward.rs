@@ -0,0 +1,105 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Forwarding messages to other rooms, like forwarding mail: the content is sent again after a
+// line saying where it came from. Files are referred to, not uploaded again, unless an
+// encrypted file is forwarded into an unencrypted room, which would publish its key.
+
+use crate::attachment;
+use crate::attachment::Attachment;
+use crate::export;
+use crate::inflight;
+use crate::rate_limited;
+use matrix_sdk::ruma::events::room::message::FormattedBody;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::OriginalRoomMessageEvent;
+use matrix_sdk::ruma::events::room::message::RoomMessageEvent;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::EventId;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use std::error::Error;
+
+pub async fn message(
+    room: &Room,
+    event_id: &EventId,
+) -> Result<OriginalRoomMessageEvent, Box<dyn Error>> {
+    let event: RoomMessageEvent = room.event(event_id).await?.event.deserialize_as()?;
+    match event {
+        RoomMessageEvent::Original(message) => Ok(message),
+        RoomMessageEvent::Redacted(_) => Err(format!("{event_id} was redacted").into()),
+    }
+}
+
+fn prepend(body: &mut String, formatted: &mut Option<FormattedBody>, header: &str) {
+    *body = format!("{header}:\n\n{body}");
+    if let Some(formatted) = formatted {
+        formatted.body = format!("<p>{header}:</p>\n{}", formatted.body);
+    }
+}
+
+// Replies and threads of the original room don't exist in the other one, so only the message
+// type is sent again. The transaction IDs follow from the message and the room, so that
+// forwarding it again, e.g. after a failure, doesn't send it twice.
+pub async fn forward(
+    client: &Client,
+    message: &OriginalRoomMessageEvent,
+    room_name: &str,
+    to: &Room,
+) -> Result<(), Box<dyn Error>> {
+    let header = format!("Forwarded from {} in {room_name}", message.sender);
+    let mut msgtype = message.content.msgtype.clone();
+    match &mut msgtype {
+        MessageType::Text(text) => prepend(&mut text.body, &mut text.formatted, &header),
+        MessageType::Notice(notice) => prepend(&mut notice.body, &mut notice.formatted, &header),
+        MessageType::Emote(emote) => prepend(&mut emote.body, &mut emote.formatted, &header),
+        _ => {
+            let content = RoomMessageEventContent::text_plain(format!("{header}:"));
+            let txn_id = inflight::derived_txn_id(&message.event_id, to.room_id(), "header");
+            rate_limited(|| async {
+                Ok::<_, Box<dyn Error>>(
+                    to.send(content.clone())
+                        .with_transaction_id(&txn_id)
+                        .await?,
+                )
+            })
+            .await?;
+        }
+    }
+    if let Some((source @ MediaSource::Encrypted(_), name, mimetype)) = export::media(message) {
+        if !to.is_encrypted().await? {
+            let data = export::fetch(client, source).await?;
+            let attachment = Attachment::new(name, mimetype.parse()?, data);
+            let txn_id = inflight::derived_txn_id(&message.event_id, to.room_id(), "attachment");
+            rate_limited(|| attachment::send(to, &attachment, &txn_id)).await?;
+            return Ok(());
+        }
+    }
+    let content = RoomMessageEventContent::new(msgtype);
+    let txn_id = inflight::derived_txn_id(&message.event_id, to.room_id(), "message");
+    rate_limited(|| async {
+        Ok::<_, Box<dyn Error>>(
+            to.send(content.clone())
+                .with_transaction_id(&txn_id)
+                .await?,
+        )
+    })
+    .await?;
+    Ok(())
+}
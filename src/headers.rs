@@ -0,0 +1,99 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// RFC 5322 style headers at the top of the message, read with -t like sendmail does, so that
+// MUAs and cron can hand over complete mails.
+
+use crate::mime;
+use std::error::Error;
+
+// The recipients as written, which may be aliases or names from the address book, like the
+// ones on the command line, e.g. root in the mail of a cron job.
+#[derive(Default)]
+pub struct Headers {
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub bcc: Vec<String>,
+    pub subject: Option<String>,
+}
+
+// Addresses are separated by commas and may have a display name, as in "Alice <@alice:example.org>".
+fn addresses(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| match (address.find('<'), address.rfind('>')) {
+            (Some(start), Some(end)) if start < end => String::from(&address[start + 1..end]),
+            _ => String::from(address),
+        })
+        .collect()
+}
+
+// Split the message into headers and body. The headers end at the first empty line, long
+// headers are continued on lines starting with whitespace. Unknown headers are ignored.
+pub fn parse(message: &str) -> Result<(Headers, String), Box<dyn Error>> {
+    let message = message.replace("\r\n", "\n");
+    let (head, body) = message.split_once("\n\n").unwrap_or((&message, ""));
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in head.lines() {
+        if line.starts_with([' ', '\t']) {
+            let Some((_, value)) = fields.last_mut() else {
+                return Err("Message doesn't start with a header".into());
+            };
+            value.push(' ');
+            value.push_str(line.trim());
+            continue;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(format!("Invalid header: {line}"))?;
+        fields.push((name.trim().to_ascii_lowercase(), String::from(value.trim())));
+    }
+    let mut headers = Headers::default();
+    for (name, value) in fields {
+        match name.as_str() {
+            "to" => headers.to.extend(addresses(&value)),
+            "cc" => headers.cc.extend(addresses(&value)),
+            "bcc" => headers.bcc.extend(addresses(&value)),
+            "subject" => headers.subject = Some(mime::decode_header(&value)),
+            _ => {}
+        }
+    }
+    Ok((headers, String::from(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continuation_lines() {
+        let message =
+            "To: Alice <@alice:example.org>,\n @bob:example.org\nSubject: disk\n\tfull\n\nbody\n";
+        let (headers, body) = parse(message).unwrap();
+        assert_eq!(headers.to, ["@alice:example.org", "@bob:example.org"]);
+        assert_eq!(headers.subject.as_deref(), Some("disk full"));
+        assert_eq!(body, "body\n");
+    }
+
+    #[test]
+    fn continuation_without_header() {
+        assert!(parse(" @alice:example.org\n\nbody\n").is_err());
+    }
+}
@@ -0,0 +1,327 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// The receive side of mailx, for rooms tagged as inbox (u.inbox): a numbered summary of the
+// unread messages, with sender, time and first line, and on a terminal the classic commands to
+// print, reply to, save the file of and delete (mark as read) them.
+
+use crate::export;
+use crate::message;
+use crate::prepare_room;
+use crate::time;
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::room::Receipts;
+use matrix_sdk::ruma::events::receipt::ReceiptThread;
+use matrix_sdk::ruma::events::receipt::ReceiptType;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::AnyMessageLikeEvent;
+use matrix_sdk::ruma::events::AnyTimelineEvent;
+use matrix_sdk::ruma::events::MessageLikeEvent;
+use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use std::error::Error;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::path::Path;
+use tokio::fs;
+
+// How far back to look for unread messages in each room.
+const MESSAGES_LIMIT: u32 = 50;
+
+pub struct Header {
+    // Seconds since the epoch.
+    time: i64,
+    room: Room,
+    room_name: String,
+    event_id: OwnedEventId,
+    sender: OwnedUserId,
+    body: String,
+    // The file of file, image, audio and video messages, with its name and MIME type.
+    media: Option<(MediaSource, String, String)>,
+    deleted: bool,
+}
+
+async fn is_inbox(room: &Room) -> Result<bool, Box<dyn Error>> {
+    Ok(room
+        .tags()
+        .await?
+        .is_some_and(|tags| tags.keys().any(|tag| tag.as_ref() == "u.inbox")))
+}
+
+// The messages after the own read receipt, oldest first.
+async fn unread(client: &Client, room: &Room) -> Result<Vec<Header>, Box<dyn Error>> {
+    let user_id = client.user_id().unwrap();
+    let read = room
+        .load_user_receipt(ReceiptType::Read, ReceiptThread::Unthreaded, user_id)
+        .await?
+        .map(|(event_id, _)| event_id);
+    let mut options = MessagesOptions::backward();
+    options.limit = UInt::from(MESSAGES_LIMIT);
+    let messages = room.messages(options).await?;
+    let room_name = room.display_name().await?.to_string();
+    let mut headers = Vec::new();
+    for event in messages.chunk {
+        let Ok(event) = event.event.deserialize() else {
+            continue;
+        };
+        if Some(event.event_id()) == read.as_deref() {
+            break;
+        }
+        let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+            MessageLikeEvent::Original(message),
+        )) = event
+        else {
+            continue;
+        };
+        // Own messages are read.
+        if &*message.sender == user_id {
+            break;
+        }
+        let media = export::media(&message);
+        headers.push(Header {
+            time: u64::from(message.origin_server_ts.as_secs()) as i64,
+            room: room.clone(),
+            room_name: room_name.clone(),
+            event_id: message.event_id,
+            sender: message.sender,
+            body: String::from(message.content.body()),
+            media,
+            deleted: false,
+        });
+    }
+    headers.reverse();
+    Ok(headers)
+}
+
+pub async fn headers(client: &Client) -> Result<Vec<Header>, Box<dyn Error>> {
+    let mut headers = Vec::new();
+    for room in client.joined_rooms() {
+        if is_inbox(&room).await? {
+            headers.extend(unread(client, &room).await?);
+        }
+    }
+    headers.sort_by_key(|header| header.time);
+    Ok(headers)
+}
+
+pub fn print_headers(headers: &[Header]) {
+    if headers.iter().all(|header| header.deleted) {
+        println!("No mail.");
+        return;
+    }
+    for (i, header) in headers.iter().enumerate() {
+        if header.deleted {
+            continue;
+        }
+        println!(
+            "{:>3} {:<30} {} {}: {}",
+            i + 1,
+            header.sender,
+            time::format_local(header.time, "%b %e %H:%M"),
+            header.room_name,
+            header.body.lines().next().unwrap_or_default()
+        );
+    }
+}
+
+fn print_message(header: &Header) {
+    println!("From: {}", header.sender);
+    println!("Room: {}", header.room_name);
+    println!(
+        "Date: {}",
+        time::format_local(header.time, "%a, %d %b %Y %H:%M:%S %z")
+    );
+    if let Some((_, name, mimetype)) = &header.media {
+        println!("Attachment: {name} ({mimetype})");
+    }
+    println!();
+    println!("{}", header.body);
+}
+
+// The reply is read up to a line with a single dot or the end of input, like mailx does.
+fn read_reply() -> Result<String, io::Error> {
+    let mut reply = String::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line == "." {
+            break;
+        }
+        reply.push_str(&line);
+        reply.push('\n');
+    }
+    Ok(String::from(reply.trim_end()))
+}
+
+async fn reply(header: &Header) -> Result<(), Box<dyn Error>> {
+    let text = read_reply()?;
+    if text.is_empty() {
+        println!("No reply sent.");
+        return Ok(());
+    }
+    prepare_room(&header.room).await?;
+    let content = RoomMessageEventContent::text_plain(text);
+    let content = message::reply(&header.room, content, &header.event_id).await?;
+    header.room.send(content).await?;
+    Ok(())
+}
+
+// The sender chooses the name, so only its last part is used, which stays in the current
+// directory. Names without one, like "" or "..", can't be used.
+fn own_name(name: &str) -> Option<&Path> {
+    Path::new(name).file_name().map(Path::new)
+}
+
+// The file is saved under its own name in the current directory by default.
+async fn save(header: &Header, file: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let Some((source, name, _)) = &header.media else {
+        return Err("No attachment.".into());
+    };
+    let file = match file {
+        Some(file) => Path::new(file),
+        None => own_name(name).ok_or(format!("Can't save as \"{name}\", give a file name."))?,
+    };
+    let data = export::fetch(&header.room.client(), source.clone()).await?;
+    fs::write(file, &data).await?;
+    println!("\"{}\" {} bytes", file.display(), data.len());
+    Ok(())
+}
+
+// A read receipt and the fully read marker, so that other clients show the messages up to the
+// event as read too.
+pub async fn mark_read(room: &Room, event_id: &OwnedEventId) -> Result<(), matrix_sdk::Error> {
+    let receipts = Receipts::new()
+        .fully_read_marker(event_id.clone())
+        .public_read_receipt(event_id.clone());
+    room.send_multiple_receipts(receipts).await
+}
+
+// The last message from someone else, to reply to.
+pub async fn last_from_others(
+    client: &Client,
+    room: &Room,
+) -> Result<Option<OwnedEventId>, Box<dyn Error>> {
+    let user_id = client.user_id().unwrap();
+    let mut options = MessagesOptions::backward();
+    options.limit = UInt::from(MESSAGES_LIMIT);
+    let messages = room.messages(options).await?;
+    for event in messages.chunk {
+        if let Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+            MessageLikeEvent::Original(message),
+        ))) = event.event.deserialize()
+        {
+            if &*message.sender != user_id {
+                return Ok(Some(message.event_id));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// The room's last event, to mark everything as read.
+pub async fn last_event(room: &Room) -> Result<Option<OwnedEventId>, Box<dyn Error>> {
+    let mut options = MessagesOptions::backward();
+    options.limit = UInt::from(1u32);
+    let messages = room.messages(options).await?;
+    match messages.chunk.first() {
+        Some(event) => Ok(event.event.get_field("event_id")?),
+        None => Ok(None),
+    }
+}
+
+// Deleting only marks the message as read, other members still see it.
+async fn delete(header: &mut Header) -> Result<(), Box<dyn Error>> {
+    mark_read(&header.room, &header.event_id).await?;
+    header.deleted = true;
+    Ok(())
+}
+
+// The command loop: p/print, r/reply and d/delete take a message number, the current message
+// by default, s/save takes a message number and a file, h/headers lists the messages again and
+// q/quit ends.
+pub async fn interact(headers: &mut [Header]) -> Result<(), Box<dyn Error>> {
+    let mut current = 0;
+    loop {
+        print!("? ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        if let Some(number) = words.next() {
+            match number.parse::<usize>() {
+                Ok(number) if (1..=headers.len()).contains(&number) => current = number - 1,
+                _ => {
+                    println!("No message {number}.");
+                    continue;
+                }
+            }
+        }
+        match command {
+            "q" | "quit" => return Ok(()),
+            "h" | "headers" => {
+                print_headers(headers);
+                continue;
+            }
+            _ => {}
+        }
+        let Some(header) = headers.get_mut(current) else {
+            println!("No messages.");
+            continue;
+        };
+        let result = match command {
+            "p" | "print" => {
+                print_message(header);
+                Ok(())
+            }
+            "r" | "reply" => reply(header).await,
+            "s" | "save" => save(header, words.next()).await,
+            "d" | "delete" => delete(header).await,
+            command => {
+                println!("Unknown command: {command}");
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            println!("{e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_names() {
+        assert_eq!(own_name("report.pdf"), Some(Path::new("report.pdf")));
+        assert_eq!(own_name("../../.bashrc"), Some(Path::new(".bashrc")));
+        assert_eq!(own_name("/etc/passwd"), Some(Path::new("passwd")));
+        for name in ["", ".", "..", "/", "a/.."] {
+            assert_eq!(own_name(name), None, "{name}");
+        }
+    }
+}
@@ -0,0 +1,105 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Stable transaction IDs, so that running matrixmail again after a crash or a failed send
+// doesn't deliver a message twice: the homeserver ignores events with a transaction ID it has
+// already seen from the device. While a message is being sent, the ID of the invocation is kept
+// in a file named after the arguments and input, and a run with the same ones soon after
+// continues it.
+
+use matrix_sdk::ruma::EventId;
+use matrix_sdk::ruma::OwnedTransactionId;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::ruma::TransactionId;
+use sha2::Digest;
+use sha2::Sha256;
+use std::env;
+use std::error::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::fs;
+
+// How long a run with the same arguments and input continues an unfinished one. Retries after
+// a crash or a temporary failure come soon, while the same message later, e.g. the same alert
+// from cron again, is a new one.
+const RETRY_TIME: Duration = Duration::from_secs(10 * 60);
+
+pub struct InFlight {
+    file: PathBuf,
+    invocation_id: String,
+}
+
+// SHA-256, which unlike std's hashers stays the same across Rust releases, so that a matrixmail
+// built in between still finds the file and sends with the same IDs.
+fn hash<'a>(parts: impl IntoIterator<Item = &'a str>) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        // Prefixed with the length, so that the parts can't run into each other.
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hasher.finalize()[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+pub async fn begin(data_dir: &Path, input: &str) -> Result<InFlight, Box<dyn Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let dir = data_dir.join("in-flight");
+    fs::create_dir_all(&dir).await?;
+    let file = dir.join(hash(args.iter().map(String::as_str).chain([input])));
+    let recent = match fs::metadata(&file).await {
+        Ok(metadata) => metadata.modified()?.elapsed().unwrap_or_default() < RETRY_TIME,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e.into()),
+    };
+    let invocation_id = match recent {
+        true => fs::read_to_string(&file).await?,
+        false => {
+            let invocation_id = TransactionId::new().to_string();
+            fs::write(&file, &invocation_id).await?;
+            invocation_id
+        }
+    };
+    Ok(InFlight {
+        file,
+        invocation_id,
+    })
+}
+
+// The ID of an event that is sent for another one, e.g. to forward it, so that it is sent only
+// once however often that is done.
+pub fn derived_txn_id(event_id: &EventId, room_id: &RoomId, kind: &str) -> OwnedTransactionId {
+    hash([event_id.as_str(), room_id.as_str(), kind]).into()
+}
+
+impl InFlight {
+    // The ID of the i-th event sent to the room, messages and attachments counted separately.
+    pub fn txn_id(&self, room_id: &RoomId, kind: &str, i: usize) -> OwnedTransactionId {
+        let i = i.to_string();
+        hash([self.invocation_id.as_str(), room_id.as_str(), kind, &i]).into()
+    }
+
+    // Everything was sent, or couldn't ever be, sending the same message again is a new message.
+    pub async fn end(self) -> Result<(), Box<dyn Error>> {
+        fs::remove_file(&self.file).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Invites to rooms, to accept them from the shell, e.g. when the account is invited to a new
+// alerts room.
+
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use matrix_sdk::RoomState;
+use std::error::Error;
+
+fn invited_room(client: &Client, room_id: &OwnedRoomId) -> Result<Room, String> {
+    client
+        .get_room(room_id)
+        .filter(|room| room.state() == RoomState::Invited)
+        .ok_or(format!("No invite to {room_id}"))
+}
+
+pub async fn list(client: &Client) -> Result<(), Box<dyn Error>> {
+    for room in client.invited_rooms() {
+        let inviter = match room.invite_details().await?.inviter {
+            Some(inviter) => inviter.user_id().to_string(),
+            None => String::from("-"),
+        };
+        println!(
+            "{}\t{inviter}\t{}",
+            room.room_id(),
+            room.display_name().await?
+        );
+    }
+    Ok(())
+}
+
+pub async fn accept(client: &Client, room_ids: &[OwnedRoomId]) -> Result<(), Box<dyn Error>> {
+    for room_id in room_ids {
+        invited_room(client, room_id)?.join().await?;
+    }
+    Ok(())
+}
+
+pub async fn reject(client: &Client, room_ids: &[OwnedRoomId]) -> Result<(), Box<dyn Error>> {
+    for room_id in room_ids {
+        invited_room(client, room_id)?.leave().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Delivery of incoming messages into a Maildir while syncing, one Maildir++ folder per room,
+// so that mail clients and indexers like mutt or notmuch can read them. Filter rules can
+// deliver into other folders, drop messages or pipe them to commands instead.
+
+use crate::export;
+use crate::gethostname;
+use crate::rules;
+use crate::rules::Action;
+use crate::rules::Rule;
+use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use std::error::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+
+// Maildir++ folders are named with a leading dot, and dots separate subfolders.
+fn folder(dir: &Path, room_name: &str) -> PathBuf {
+    let name: String = room_name
+        .chars()
+        .map(|c| match c {
+            '.' | '/' => '_',
+            c => c,
+        })
+        .collect();
+    dir.join(format!(".{name}"))
+}
+
+// Written to tmp first and then moved to new, so that readers never see half a message.
+async fn deliver(folder: &Path, text: &str) -> Result<(), Box<dyn Error>> {
+    for subdir in ["tmp", "new", "cur"] {
+        fs::create_dir_all(folder.join(subdir)).await?;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let name = format!(
+        "{}.M{}P{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        gethostname()?
+    );
+    let tmp = folder.join("tmp").join(&name);
+    fs::write(&tmp, text).await?;
+    fs::rename(&tmp, folder.join("new").join(&name)).await?;
+    Ok(())
+}
+
+// Deliver the messages of every following sync.
+pub fn watch(client: &Client, dir: &Path, rules: Vec<Rule>) {
+    let dir = dir.to_path_buf();
+    let rules = Arc::new(rules);
+    client.add_event_handler(
+        move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+            let dir = dir.clone();
+            let rules = rules.clone();
+            async move {
+                let result = async {
+                    let room_name = room.display_name().await?.to_string();
+                    let message = event.into_full_event(room.room_id().to_owned());
+                    let action = rules::action(
+                        &rules,
+                        message.room_id.as_str(),
+                        &room_name,
+                        message.sender.as_str(),
+                        message.content.body(),
+                    );
+                    if let Some(Action::Drop) = action {
+                        return Ok(());
+                    }
+                    let (_, _, text) = export::mail(&client, &room_name, &message).await;
+                    match action {
+                        Some(Action::Folder(name)) => deliver(&folder(&dir, name), &text).await,
+                        Some(Action::Pipe(command)) => rules::pipe(command, &text).await,
+                        _ => deliver(&folder(&dir, &room_name), &text).await,
+                    }
+                };
+                if let Err(e) = result.await {
+                    eprintln!("Error delivering message to {}: {e}", dir.display());
+                }
+            }
+        },
+    );
+}
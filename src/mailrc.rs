@@ -0,0 +1,217 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// The mailx startup file, ~/.mailrc or $MAILRC, so that existing mailx setups keep working:
+// alias (or group) lines name lists of addresses, set asksub asks for a missing subject on a
+// terminal and set record=FILE appends sent messages to an mbox file. Other variables and
+// commands are for mailx features matrixmail doesn't have, and are ignored.
+
+use crate::address;
+use crate::address::Address;
+use crate::addressbook;
+use crate::addressbook::AddressBook;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use tokio::fs;
+
+// How deep aliases may refer to other aliases, which may be lists of addresses and aliases, to
+// stop at loops.
+const ALIAS_DEPTH: usize = 10;
+
+#[derive(Default)]
+pub struct Mailrc {
+    pub asksub: bool,
+    pub record: Option<PathBuf>,
+    aliases: HashMap<String, Vec<String>>,
+}
+
+fn home() -> PathBuf {
+    PathBuf::from(env::var("HOME").unwrap_or_default())
+}
+
+// A line's words, which may be quoted with " or '.
+fn words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = None;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+fn parse(text: &str) -> Mailrc {
+    let mut mailrc = Mailrc::default();
+    // Lines ending with a backslash continue on the next one.
+    let text = text.replace("\\\n", " ");
+    for line in text.lines() {
+        let line = line.trim_start();
+        if line.starts_with('#') {
+            continue;
+        }
+        let words = words(line);
+        let Some((command, arguments)) = words.split_first() else {
+            continue;
+        };
+        match command.as_str() {
+            "alias" | "a" | "group" | "g" => {
+                if let Some((name, addresses)) = arguments.split_first() {
+                    let entry = mailrc.aliases.entry(name.clone()).or_default();
+                    entry.extend(addresses.iter().cloned());
+                }
+            }
+            "set" | "se" => {
+                for argument in arguments {
+                    match argument.split_once('=') {
+                        Some(("record", file)) => {
+                            mailrc.record = match file.strip_prefix("~/") {
+                                Some(file) => Some(home().join(file)),
+                                None => Some(PathBuf::from(file)),
+                            }
+                        }
+                        None if argument == "asksub" => mailrc.asksub = true,
+                        None if argument == "noasksub" => mailrc.asksub = false,
+                        _ => {}
+                    }
+                }
+            }
+            "unset" | "uns" => {
+                for argument in arguments {
+                    match argument.as_str() {
+                        "asksub" => mailrc.asksub = false,
+                        "record" => mailrc.record = None,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    mailrc
+}
+
+pub async fn load() -> Result<Mailrc, Box<dyn Error>> {
+    let file = match env::var_os("MAILRC") {
+        Some(file) => PathBuf::from(file),
+        None => home().join(".mailrc"),
+    };
+    match fs::read_to_string(&file).await {
+        Ok(text) => Ok(parse(&text)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Mailrc::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// An address, or a name from the address book.
+fn add(name: &str, book: &AddressBook, addresses: &mut Vec<Address>) -> Result<(), String> {
+    let address = addressbook::lookup(book, name).unwrap_or(name);
+    let address = address.parse().map_err(|e| format!("{name}: {e}"))?;
+    // Lists that share members send to them once.
+    if !address::contains(addresses, &address) {
+        addresses.push(address);
+    }
+    Ok(())
+}
+
+impl Mailrc {
+    // Replace the alias, e.g. with one from the configuration.
+    pub fn set_alias(&mut self, name: &str, addresses: Vec<String>) {
+        self.aliases.insert(String::from(name), addresses);
+    }
+
+    pub fn aliases(&self) -> impl Iterator<Item = &str> {
+        self.aliases.keys().map(String::as_str)
+    }
+
+    fn expand_into(
+        &self,
+        name: &str,
+        book: &AddressBook,
+        depth: usize,
+        addresses: &mut Vec<Address>,
+    ) -> Result<(), String> {
+        match self.aliases.get(name) {
+            Some(_) if depth == ALIAS_DEPTH => Err(format!("Alias loop at {name}")),
+            // Like in mailx, an alias that lists itself means the name itself there, e.g. to send
+            // to a name from the address book and more with "alias ops ops @alice:example.org".
+            Some(names) => names.iter().try_for_each(|member| match member == name {
+                true => add(member, book, addresses),
+                false => self.expand_into(member, book, depth + 1, addresses),
+            }),
+            None => add(name, book, addresses),
+        }
+    }
+
+    // The addresses, with aliases replaced by theirs and names from the address book by theirs.
+    pub fn expand(&self, names: &[String], book: &AddressBook) -> Result<Vec<Address>, String> {
+        let mut addresses = Vec::new();
+        for name in names {
+            self.expand_into(name, book, 0, &mut addresses)?;
+        }
+        Ok(addresses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_words() {
+        assert_eq!(
+            words(r#"alias ops "Alice Smith" 'a b'c "" d"#),
+            ["alias", "ops", "Alice Smith", "a bc", "", "d"]
+        );
+    }
+
+    #[test]
+    fn alias_of_itself() {
+        let mailrc =
+            parse("alias ops ops @alice:example.org\nalias loop other\nalias other loop\n");
+        let book: AddressBook = toml::from_str("[ops]\naddress = \"#ops:example.org\"").unwrap();
+        let addresses = mailrc.expand(&[String::from("ops")], &book).unwrap();
+        let addresses: Vec<String> = addresses.iter().map(Address::to_string).collect();
+        assert_eq!(addresses, ["#ops:example.org", "@alice:example.org"]);
+        assert!(mailrc.expand(&[String::from("loop")], &book).is_err());
+    }
+
+    #[test]
+    fn startup_file() {
+        let mailrc = parse(
+            "# aliases\nalias ops @alice:example.org \\\n  @bob:example.org\ng ops #ops:example.org\nset asksub record=/tmp/sent\n",
+        );
+        assert_eq!(
+            mailrc.aliases["ops"],
+            ["@alice:example.org", "@bob:example.org", "#ops:example.org"]
+        );
+        assert!(mailrc.asksub);
+        assert_eq!(mailrc.record, Some(PathBuf::from("/tmp/sent")));
+    }
+}
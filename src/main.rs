@@ -16,94 +16,1017 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use address::Address;
+use age::secrecy::Secret;
+use attachment::Attachment;
 use clap::Parser;
-use libc;
+use clap::Subcommand;
+use futures_util::stream;
+use futures_util::StreamExt;
+use inflight::InFlight;
+use mailrc::Mailrc;
+use matrix_sdk::config::RequestConfig;
 use matrix_sdk::config::SyncSettings;
 use matrix_sdk::matrix_auth::MatrixSession;
 use matrix_sdk::matrix_auth::MatrixSessionTokens;
+use matrix_sdk::ruma::api::client::account::whoami;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
 use matrix_sdk::ruma::api::client::filter::FilterDefinition;
+use matrix_sdk::ruma::api::client::session::get_login_types::v3::LoginType;
+use matrix_sdk::ruma::api::client::uiaa;
+use matrix_sdk::ruma::api::client::uiaa::AuthData;
+use matrix_sdk::ruma::api::client::uiaa::UiaaInfo;
+use matrix_sdk::ruma::api::client::uiaa::UserIdentifier;
+use matrix_sdk::ruma::events::reaction::ReactionEventContent;
+use matrix_sdk::ruma::events::relation::Annotation;
 use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::EventId;
 use matrix_sdk::ruma::OwnedDeviceId;
+use matrix_sdk::ruma::OwnedEventId;
 use matrix_sdk::ruma::OwnedRoomId;
 use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::ServerName;
+use matrix_sdk::sync::SyncResponse;
 use matrix_sdk::Client;
+use matrix_sdk::ClientBuilder;
+use matrix_sdk::Room;
 use matrix_sdk::RoomState;
 use matrix_sdk::SessionMeta;
+use message::Location;
+use reqwest::Certificate;
 use serde::Deserialize;
 use serde::Serialize;
-use serde_json;
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
+use std::fmt::Display;
+use std::future::Future;
 use std::io;
+use std::io::BufRead;
+use std::io::Read;
 use std::io::Write;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::path::PathBuf;
-use termios;
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
+use sticker::Sticker;
 use tokio::fs;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
+use trust::Trust;
 use url::Url;
 
+mod address;
+mod addressbook;
+mod attachment;
+mod completions;
+mod config;
+mod confirm;
+mod crypto;
+mod devices;
+mod expire;
+mod export;
+mod forward;
+mod headers;
+mod inbox;
+mod inflight;
+mod invites;
+mod maildir;
+mod mailrc;
+mod media;
+mod message;
+mod mime;
+mod oidc;
+mod queue;
+mod receipts;
+mod record;
+mod register;
+mod rooms;
+mod rules;
+mod search;
+mod state;
+mod sticker;
+mod sysexits;
+mod tail;
+mod template;
+mod time;
+mod trust;
+mod verify;
+
+// Application service users don't log in, so they have no device of their own.
+const APPSERVICE_DEVICE_ID: &str = "MATRIXMAIL";
+
+// Exit status when the session is no longer valid.
+const EXIT_SESSION_EXPIRED: i32 = sysexits::EX_NOPERM;
+
+// Exit status when --wait-read timed out.
+const EXIT_NOT_READ: i32 = sysexits::EX_TEMPFAIL;
+
+// Messages expiring within this many seconds are redacted without waiting for `reap`.
+const EXPIRE_WAIT: i64 = 60;
+
 // Struct for Session and homeserver.
 // Store the homeserver explicitly because it might not be discoverable from the user ID.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Session {
     // Serialize is not implemented for Url
     homeserver: String,
     user_id: OwnedUserId,
     device_id: OwnedDeviceId,
+    #[serde(default)]
     access_token: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     refresh_token: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     sync_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oidc: Option<oidc::OidcRegistration>,
+    // Keep the tokens in the OS keyring instead of this file.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    keyring: bool,
+    // The passphrase the file is encrypted with.
+    #[serde(skip)]
+    passphrase: Option<String>,
+    // Where to get the password to resume the session after a soft logout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password_file: Option<PathBuf>,
+    // The application service registration with the as_token to use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    appservice: Option<PathBuf>,
 }
 
-#[derive(Parser, Debug)]
+// The secrets stored in the keyring.
+#[derive(Serialize, Deserialize, Debug)]
+struct SessionTokens {
+    access_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+}
+
+fn keyring_entry(session: &Session) -> Result<keyring::Entry, keyring::Error> {
+    keyring::Entry::new(
+        "matrixmail",
+        &format!("{}/{}", session.user_id, session.device_id),
+    )
+}
+
+#[derive(Parser, Clone, Debug)]
 #[command(disable_help_flag = true)]
 struct Args {
     /// The message subject
     #[arg(short)]
     subject: Option<String>,
 
-    /// The recipient address
-    #[arg(required = true, num_args = 1..)]
-    addresses: Vec<OwnedRoomId>,
+    /// The account profile
+    #[arg(short = 'A')]
+    profile: Option<String>,
+
+    /// Keep the session and the store in this directory
+    #[arg(long, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// The session file, by default login in the data directory
+    #[arg(long, value_name = "FILE")]
+    session_file: Option<PathBuf>,
+
+    /// Give up after this many seconds altogether, with exit status 75
+    #[arg(long, value_name = "SECS")]
+    deadline: Option<u64>,
+
+    #[command(flatten)]
+    network: NetworkArgs,
+
+    /// Also send a copy of the message, with its recipients, to this room
+    #[arg(long, value_name = "ADDRESS", conflicts_with = "event_type")]
+    sent_room: Option<Address>,
+
+    /// Append sent messages to this mbox file
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Append sent messages to an mbox file named after the first recipient
+    #[arg(short = 'F', conflicts_with = "record")]
+    record_by_recipient: bool,
+
+    /// Only tell by the exit status whether there are unread messages
+    #[arg(short = 'e', conflicts_with_all = ["addresses", "cc", "bcc", "read_recipients"])]
+    check: bool,
+
+    /// Don't send messages with an empty body
+    #[arg(short = 'E')]
+    discard_empty: bool,
+
+    /// Don't append the signature file
+    #[arg(long)]
+    no_sig: bool,
+
+    /// Attach a file, sent after the message
+    #[arg(short = 'a', value_name = "FILE")]
+    attachments: Vec<PathBuf>,
+
+    /// Which devices in encrypted rooms to trust with the message
+    #[arg(long, value_enum, default_value_t)]
+    trust: Trust,
+
+    /// Lay out the message with a template file, with {{subject}}, {{body}}, {{hostname}},
+    /// {{date}}, {{env.NAME}}, {{room}} and {{address}} replaced
+    #[arg(long, value_name = "FILE", conflicts_with = "event_type")]
+    template: Option<PathBuf>,
+
+    /// Format the message as Markdown
+    #[arg(long)]
+    markdown: bool,
+
+    /// Don't format the message as Markdown, even if configured
+    #[arg(long, conflicts_with = "markdown")]
+    no_markdown: bool,
+
+    /// The message type, like m.text, m.notice or a custom one
+    #[arg(long, conflicts_with_all = ["notice", "emote"])]
+    msgtype: Option<String>,
+
+    /// Send a location, given as LAT,LON[;DESCRIPTION], described by the message if not given
+    #[arg(
+        long,
+        value_name = "LOCATION",
+        conflicts_with_all = ["markdown", "notice", "emote", "msgtype"]
+    )]
+    location: Option<Location>,
+
+    /// Send an event of this type, with the message as JSON content
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "subject", "markdown", "notice", "emote", "msgtype", "mentions", "in_reply_to",
+            "thread", "thread_latest", "edit", "edit_last", "attachments", "cc", "location",
+            "no_preview"
+        ]
+    )]
+    event_type: Option<String>,
+
+    /// Send a sticker, as mxc: URI or image file, described by the message
+    #[arg(
+        long,
+        value_name = "MXC_OR_FILE",
+        conflicts_with_all = [
+            "event_type", "location", "markdown", "notice", "emote", "msgtype", "mentions",
+            "in_reply_to", "thread", "thread_latest", "edit", "edit_last", "no_preview"
+        ]
+    )]
+    sticker: Option<Sticker>,
+
+    /// Send a poll with this question after the message
+    #[arg(
+        long,
+        value_name = "QUESTION",
+        requires = "options",
+        conflicts_with_all = ["event_type", "sticker", "edit", "edit_last"]
+    )]
+    poll: Option<String>,
+
+    /// An option to choose in the poll
+    #[arg(long = "option", value_name = "OPTION", requires = "poll")]
+    options: Vec<String>,
+
+    /// Ask clients not to show previews of the links in the message
+    #[arg(long)]
+    no_preview: bool,
+
+    /// Send the message as notice, which bots ignore and clients may not notify about
+    #[arg(long)]
+    notice: bool,
+
+    /// Send the message as emote, like /me
+    #[arg(long, conflicts_with = "notice")]
+    emote: bool,
+
+    /// Mention a user, notifying them
+    #[arg(long = "mention", value_name = "USER_ID")]
+    mentions: Vec<OwnedUserId>,
+
+    /// Send the message as reply to an event
+    #[arg(long, value_name = "EVENT_ID")]
+    in_reply_to: Option<OwnedEventId>,
+
+    /// Send the message in the thread of an event
+    #[arg(long, value_name = "EVENT_ID", conflicts_with = "in_reply_to")]
+    thread: Option<OwnedEventId>,
+
+    /// Send the message in the last thread started with this option, or start one
+    #[arg(long, conflicts_with_all = ["in_reply_to", "thread"])]
+    thread_latest: bool,
+
+    /// Replace an earlier message with this one
+    #[arg(
+        long,
+        value_name = "EVENT_ID",
+        conflicts_with_all = ["in_reply_to", "thread", "thread_latest"]
+    )]
+    edit: Option<OwnedEventId>,
+
+    /// Replace the last message sent to the room with this one
+    #[arg(long, conflicts_with_all = ["in_reply_to", "thread", "thread_latest", "edit"])]
+    edit_last: bool,
+
+    /// Split messages larger than this many bytes into several
+    #[arg(long, value_name = "BYTES", default_value_t = 32768)]
+    split_size: usize,
+
+    /// Redact the message after a time, like 30s, 10m, 2h or 1d, with `reap` or by waiting for
+    /// up to a minute
+    #[arg(long, value_name = "TTL", value_parser = queue::parse_delay, conflicts_with = "edit")]
+    expire: Option<i64>,
+
+    /// Print the room ID and event ID of each sent message
+    #[arg(long)]
+    print_ids: bool,
+
+    /// Print each step of the delivery to stderr
+    #[arg(short = 'v')]
+    verbose: bool,
+
+    /// Show the rooms and the start of the message on the terminal and ask before sending
+    #[arg(long)]
+    confirm: bool,
+
+    /// Print the names to complete recipients with, for the completion scripts
+    #[arg(long, hide = true)]
+    complete_addresses: bool,
+
+    /// Send to at most this many rooms at the same time
+    #[arg(long, value_name = "N", default_value_t = 8, value_parser = clap::value_parser!(u16).range(1..))]
+    max_concurrency: u16,
+
+    /// Wait until someone else has read the message, exiting with 75 after the timeout
+    #[arg(
+        long,
+        value_name = "SECS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "300"
+    )]
+    wait_read: Option<u64>,
+
+    /// Queue the message and send it after a delay, like 30s, 10m, 2h or 1d, with `flush`
+    #[arg(long, value_parser = queue::parse_delay)]
+    delay: Option<i64>,
+
+    /// Queue the message and send it at a local time (YYYY-MM-DD HH:MM), with `flush`
+    #[arg(long, value_name = "TIME", value_parser = queue::parse_time, conflicts_with = "delay")]
+    send_at: Option<i64>,
+
+    /// Refuse to send to rooms that are not end-to-end encrypted
+    #[arg(long)]
+    require_encryption: bool,
+
+    /// Send a copy to the addresses, which are shown to the other recipients
+    #[arg(short = 'c', value_name = "ADDRESSES", value_delimiter = ',')]
+    cc: Vec<String>,
+
+    /// Send a blind copy to the addresses
+    #[arg(short = 'b', value_name = "ADDRESSES", value_delimiter = ',')]
+    bcc: Vec<String>,
+
+    /// Read recipients and subject from the To:, Cc:, Bcc: and Subject: headers of the message
+    #[arg(short = 't')]
+    read_recipients: bool,
+
+    /// The recipient addresses: room IDs, room aliases, user IDs to send a direct message to, or
+    /// aliases from ~/.mailrc. Without any, the unread messages in the inbox rooms are listed
+    addresses: Vec<String>,
+}
+
+// How to reach the homeserver, the same in send mode and for the commands.
+#[derive(clap::Args, Clone, Debug, Default)]
+struct NetworkArgs {
+    /// Give up on an HTTP request to the homeserver after this many seconds
+    #[arg(long, value_name = "SECS", global = true)]
+    timeout: Option<u64>,
+
+    /// Reach the homeserver through this HTTP, HTTPS or SOCKS5 proxy
+    #[arg(long, value_name = "URL", global = true)]
+    proxy: Option<String>,
+
+    /// Also trust the CA certificates in this PEM file, e.g. of an internal CA
+    #[arg(long, value_name = "FILE", global = true)]
+    ca_file: Option<PathBuf>,
+
+    /// Only trust the certificates in this PEM file, pinning the homeserver's certificate
+    #[arg(long, value_name = "FILE", global = true, conflicts_with = "ca_file")]
+    pin_cert: Option<PathBuf>,
+
+    /// Don't verify the homeserver's certificate at all, for test setups only
+    #[arg(long, global = true, conflicts_with_all = ["ca_file", "pin_cert"])]
+    insecure: bool,
+
+    /// Connect through Tor, at socks5h://127.0.0.1:9050 unless --proxy is given
+    #[arg(long, global = true)]
+    tor: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The account profile
+    #[arg(short = 'A', long, global = true)]
+    profile: Option<String>,
+
+    /// Keep the session and the store in this directory
+    #[arg(long, global = true, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// The session file, by default login in the data directory
+    #[arg(long, global = true, value_name = "FILE")]
+    session_file: Option<PathBuf>,
+
+    /// Give up after this many seconds altogether, with exit status 75
+    #[arg(long, value_name = "SECS", global = true)]
+    deadline: Option<u64>,
+
+    #[command(flatten)]
+    network: NetworkArgs,
+
+    /// Send the queued messages that are due, like flush
+    #[arg(short = 'q')]
+    queue: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Log in and save the session (default)
+    Login(LoginArgs),
+    /// Log out and delete the session and the local store
+    Logout,
+    /// Show the session and check whether the access token is valid
+    Whoami,
+    /// Register a new account and save the session
+    Register(RegisterArgs),
+    /// Manage the account's devices
+    Devices {
+        #[command(subcommand)]
+        command: DevicesCommand,
+    },
+    /// List, accept or reject invites to rooms
+    Invites {
+        #[command(subcommand)]
+        command: InvitesCommand,
+    },
+    /// Manage the encryption keys
+    Crypto {
+        #[command(subcommand)]
+        command: CryptoCommand,
+    },
+    /// Send the queued messages that are due
+    #[command(alias = "flush-queue")]
+    Flush,
+    /// List the unread messages in the rooms tagged as inbox
+    Read,
+    /// Export the messages of a room to a file
+    Export {
+        /// The room to export
+        #[arg(long)]
+        room: Address,
+        /// Leave out messages before this time, like 2024-01-01
+        #[arg(long, value_name = "TIME", value_parser = queue::parse_time)]
+        since: Option<i64>,
+        #[arg(long, value_enum, default_value_t)]
+        format: export::Format,
+        /// Also save the files of the messages into the directory
+        #[arg(long, value_name = "DIR")]
+        download_media: Option<PathBuf>,
+        file: PathBuf,
+    },
+    /// Keep syncing and deliver incoming messages into a Maildir, a folder per room
+    Maildir {
+        dir: PathBuf,
+        /// Filter rules for the messages, by default ~/.config/matrixmail/rules.yaml if it exists
+        #[arg(long, value_name = "FILE")]
+        rules: Option<PathBuf>,
+    },
+    /// Keep syncing and print incoming messages, in the rooms or in all rooms
+    Tail {
+        addresses: Vec<Address>,
+        #[arg(long, value_enum, default_value_t)]
+        format: tail::Format,
+    },
+    /// List the joined rooms
+    Rooms {
+        #[arg(long, value_enum, default_value_t)]
+        format: rooms::Format,
+    },
+    /// Leave a room the account no longer needs
+    Leave {
+        address: Address,
+        /// Also forget the room, so that it's not synced anymore
+        #[arg(long)]
+        forget: bool,
+    },
+    /// Show the details of a room, like encryption and whether messages can be sent
+    RoomInfo { address: Address },
+    /// Print the messages containing the pattern, in the rooms or in all rooms
+    Search {
+        pattern: String,
+        /// A room to search, can be repeated
+        #[arg(long = "room", value_name = "ADDRESS")]
+        rooms: Vec<Address>,
+        /// Leave out messages before this time, like 2024-01-01
+        #[arg(long, value_name = "TIME", value_parser = queue::parse_time)]
+        since: Option<i64>,
+    },
+    /// Redact the messages sent with --expire whose time is up
+    Reap,
+    /// React to a message, e.g. with ✅ to acknowledge an alert
+    React {
+        address: Address,
+        event_id: OwnedEventId,
+        key: String,
+    },
+    /// Mark the messages of a room as read, up to the event or all of them
+    MarkRead {
+        address: Address,
+        event_id: Option<OwnedEventId>,
+    },
+    /// Send a message of a room to other rooms again, saying where it came from
+    Forward {
+        address: Address,
+        event_id: OwnedEventId,
+        #[arg(required = true, num_args = 1..)]
+        to: Vec<Address>,
+    },
+    /// Reply to the last message from someone else in a room with the input
+    Reply { address: Address },
+    /// Redact a message, e.g. one sent with the wrong content
+    Redact {
+        address: Address,
+        event_id: OwnedEventId,
+        /// Why the message was redacted
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Verify this device by comparing emojis with another device of the account
+    Verify {
+        /// The device to verify with, by default any device that accepts
+        device_id: Option<OwnedDeviceId>,
+    },
+    /// Print the shell completion script for matrixmail and for mail and mailx
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DevicesCommand {
+    /// List all devices
+    List,
+    /// Set the display name of a device
+    Rename {
+        device_id: OwnedDeviceId,
+        display_name: String,
+    },
+    /// Delete devices, logging them out
+    Delete {
+        #[arg(required = true, num_args = 1..)]
+        device_ids: Vec<OwnedDeviceId>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum InvitesCommand {
+    /// List the invites with room ID, inviter and room name
+    List,
+    /// Join the rooms
+    Accept {
+        #[arg(required = true, num_args = 1..)]
+        room_ids: Vec<OwnedRoomId>,
+    },
+    /// Decline the invites
+    Reject {
+        #[arg(required = true, num_args = 1..)]
+        room_ids: Vec<OwnedRoomId>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CryptoCommand {
+    /// Create cross-signing keys and sign this device with them
+    Bootstrap {
+        /// Replace existing cross-signing keys, which unverifies all other devices
+        #[arg(long)]
+        reset: bool,
+    },
+    /// Import the cross-signing and backup keys from secret storage with the recovery key
+    Unlock,
+    /// Restore the room keys from the key backup with the recovery key
+    RestoreBackup,
+    /// Export the room keys to a passphrase-protected file, like Element does
+    ExportKeys { file: PathBuf },
+    /// Import room keys from a passphrase-protected file exported by Element or matrixmail
+    ImportKeys { file: PathBuf },
+    /// Answer room key requests from the account's verified devices
+    ServeKeys {
+        /// How long to keep answering
+        #[arg(long = "for", value_name = "SECS", default_value_t = 60)]
+        seconds: u64,
+    },
+}
+
+#[derive(clap::Args, Debug, Default)]
+struct LoginArgs {
+    /// The homeserver URL
+    #[arg(long)]
+    homeserver: Option<String>,
+
+    /// The user ID
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Use an existing access token instead of logging in
+    #[arg(long, requires_all = ["homeserver", "user", "device_id"])]
+    access_token: Option<String>,
+
+    /// The device ID of the access token
+    #[arg(
+        long,
+        requires_all = ["homeserver", "user"],
+        conflicts_with_all = ["appservice", "password_file", "password_fd"]
+    )]
+    device_id: Option<OwnedDeviceId>,
+
+    /// Act as the sender user of an application service, using the as_token of its registration file
+    #[arg(
+        long,
+        value_name = "REGISTRATION",
+        requires_all = ["homeserver", "user"],
+        conflicts_with_all = ["access_token", "password_file", "password_fd"]
+    )]
+    appservice: Option<PathBuf>,
+
+    /// Import a session (homeserver, user_id, device_id, access_token) exported from another client
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["user", "access_token", "appservice", "password_file", "password_fd"]
+    )]
+    import: Option<PathBuf>,
+
+    /// Read the password from a file instead of $MATRIXMAIL_PASSWORD or the terminal
+    #[arg(long, conflicts_with = "access_token")]
+    password_file: Option<PathBuf>,
+
+    /// Read the password from a file descriptor
+    #[arg(long, value_parser = parse_password_fd, conflicts_with_all = ["access_token", "password_file"])]
+    password_fd: Option<RawFd>,
+
+    /// The device name
+    #[arg(long, conflicts_with = "access_token")]
+    device_name: Option<String>,
+
+    /// The device display name
+    #[arg(long, conflicts_with = "access_token")]
+    display_name: Option<String>,
+
+    /// Store the access and refresh tokens in the OS keyring
+    #[arg(long)]
+    keyring: bool,
+
+    /// Encrypt the session file with a passphrase
+    #[arg(long)]
+    encrypt: bool,
+
+    /// Log in by approving a code on another device, on homeservers with OIDC login
+    #[arg(long, conflicts_with_all = ["user", "access_token", "appservice", "import"])]
+    other_device: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct RegisterArgs {
+    /// The homeserver URL
+    #[arg(long)]
+    homeserver: Option<String>,
+
+    /// The user name
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Read the password from a file instead of $MATRIXMAIL_PASSWORD or the terminal
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+
+    /// The registration token, if the homeserver requires one
+    #[arg(long)]
+    registration_token: Option<String>,
+
+    /// The device name
+    #[arg(long)]
+    device_name: Option<String>,
+
+    /// The device display name
+    #[arg(long)]
+    display_name: Option<String>,
+
+    /// Store the access and refresh tokens in the OS keyring
+    #[arg(long)]
+    keyring: bool,
+
+    /// Encrypt the session file with a passphrase
+    #[arg(long)]
+    encrypt: bool,
+}
+
+// Get the session file passphrase from $MATRIXMAIL_PASSPHRASE_COMMAND or the terminal.
+fn session_passphrase(confirm: bool) -> Result<String, Box<dyn Error>> {
+    if let Ok(command) = env::var("MATRIXMAIL_PASSPHRASE_COMMAND") {
+        let output = process::Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stderr(process::Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("Passphrase command failed: {}", output.status).into());
+        }
+        let passphrase = String::from_utf8(output.stdout)?;
+        return Ok(String::from(
+            passphrase.strip_suffix("\n").unwrap_or(&passphrase),
+        ));
+    }
+    let passphrase = getpass_tty("Session passphrase: ")?;
+    if confirm && getpass_tty("Repeat passphrase: ")? != passphrase {
+        return Err("Passphrases do not match".into());
+    }
+    Ok(passphrase)
+}
+
+fn decrypt_session(buffer: &[u8], passphrase: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let decryptor = match age::Decryptor::new(buffer)? {
+        age::Decryptor::Passphrase(decryptor) => decryptor,
+        _ => return Err("Session file is not encrypted with a passphrase".into()),
+    };
+    let mut reader = decryptor.decrypt(&Secret::new(String::from(passphrase)), None)?;
+    let mut plaintext = Vec::new();
+    reader.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+fn encrypt_session(buffer: &[u8], passphrase: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let encryptor = age::Encryptor::with_user_passphrase(Secret::new(String::from(passphrase)));
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut ciphertext)?;
+    writer.write_all(buffer)?;
+    writer.finish()?;
+    Ok(ciphertext)
+}
+
+// The as_token from an application service registration file.
+async fn appservice_token(registration: &Path) -> Result<String, Box<dyn Error>> {
+    #[derive(Deserialize)]
+    struct Registration {
+        as_token: String,
+    }
+    let registration: Registration =
+        serde_yaml::from_str(&fs::read_to_string(registration).await?)?;
+    Ok(registration.as_token)
 }
 
 async fn load_session(file: &Path) -> Result<Session, Box<dyn Error>> {
     let mut f = File::open(file).await?;
     let mut buffer = Vec::new();
     f.read_to_end(&mut buffer).await?;
-    let session = serde_json::from_slice(&buffer)?;
+    let mut passphrase = None;
+    if buffer.starts_with(b"age-encryption.org/") {
+        let p = session_passphrase(false)?;
+        buffer = decrypt_session(&buffer, &p)?;
+        passphrase = Some(p);
+    }
+    let mut session: Session = serde_json::from_slice(&buffer)?;
+    session.passphrase = passphrase;
+    if let Some(registration) = &session.appservice {
+        session.access_token = appservice_token(registration).await?;
+    }
+    if session.keyring {
+        let tokens: SessionTokens =
+            serde_json::from_str(&keyring_entry(&session)?.get_password()?)?;
+        session.access_token = tokens.access_token;
+        session.refresh_token = tokens.refresh_token;
+    }
     Ok(session)
 }
 
 async fn save_session(file: &Path, session: &Session) -> Result<(), Box<dyn Error>> {
+    let mut value = serde_json::to_value(session)?;
+    if session.keyring {
+        let tokens = SessionTokens {
+            access_token: session.access_token.clone(),
+            refresh_token: session.refresh_token.clone(),
+        };
+        keyring_entry(session)?.set_password(&serde_json::to_string(&tokens)?)?;
+        let object = value.as_object_mut().unwrap();
+        object.remove("access_token");
+        object.remove("refresh_token");
+    }
+    if session.appservice.is_some() {
+        value.as_object_mut().unwrap().remove("access_token");
+    }
+    let mut buffer = serde_json::to_vec(&value)?;
+    if let Some(passphrase) = &session.passphrase {
+        buffer = encrypt_session(&buffer, passphrase)?;
+    }
     fs::create_dir_all(file.parent().unwrap()).await?;
     let mut f = File::create(file).await?;
-    let buffer = serde_json::to_vec(session)?;
     f.write_all(&buffer).await?;
     Ok(())
 }
 
+// What is sent to a room: the message, as text and maybe HTML, or a sticker, and the
+// attachments after it.
+#[derive(Clone, Copy)]
+struct Mail<'a> {
+    text: &'a template::Message,
+    html: Option<&'a str>,
+    // The address book entry of the recipient.
+    entry: Option<(&'a str, &'a addressbook::Entry)>,
+    sticker: Option<&'a sticker::Upload>,
+    attachments: &'a [Attachment],
+    inline: &'a attachment::Inline,
+}
+
+// Before sending to the room, whether it is encrypted. The room key is shared with the devices
+// of all members, which lazy loading leaves out.
+async fn prepare_room(room: &Room) -> Result<bool, Box<dyn Error>> {
+    let encrypted = room.is_encrypted().await?;
+    if encrypted {
+        room.sync_members().await?;
+    }
+    Ok(encrypted)
+}
+
 async fn send_message(
     client: &Client,
-    room_id: &OwnedRoomId,
-    message: &str,
-) -> Result<(), Box<dyn Error>> {
-    let room = match client
-        .get_room(room_id)
-        .filter(|room| room.state() == RoomState::Joined)
-    {
-        Some(room) => room,
-        None => client.join_room_by_id(room_id).await?,
-    };
-    let content = RoomMessageEventContent::text_plain(message);
-    room.send(content).await?;
-    Ok(())
+    address: &Address,
+    mail: Mail<'_>,
+    args: &Args,
+    data_dir: &Path,
+    in_flight: &InFlight,
+) -> Result<(OwnedRoomId, Vec<OwnedEventId>), Box<dyn Error>> {
+    let room = address::room(client, address, data_dir).await?;
+    let room_id = room.room_id();
+    if let Some(event_type) = &args.event_type {
+        let content: serde_json::Value = serde_json::from_str(&mail.text.to_string())?;
+        let txn_id = in_flight.txn_id(room_id, "message", 0);
+        let response = rate_limited(|| async {
+            Ok::<_, Box<dyn Error>>(
+                room.send_raw(event_type, content.clone())
+                    .with_transaction_id(&txn_id)
+                    .await?,
+            )
+        })
+        .await?;
+        if args.print_ids {
+            println!("{room_id} {}", response.event_id);
+        }
+        return Ok((room_id.to_owned(), vec![response.event_id]));
+    }
+    let mut event_ids = Vec::new();
+    let encrypted = prepare_room(&room).await?;
+    if !encrypted && args.require_encryption {
+        return Err(format!("Room {room_id} is not encrypted").into());
+    }
+    trust::check(client, &room, args.trust, data_dir).await?;
+    if let Some(sticker) = mail.sticker {
+        let sticker = sticker.content(client, encrypted).await?;
+        let txn_id = in_flight.txn_id(room_id, "sticker", 0);
+        let response = rate_limited(|| async {
+            Ok::<_, Box<dyn Error>>(match sticker.clone() {
+                sticker::Content::Sticker(content) => {
+                    room.send(content).with_transaction_id(&txn_id).await?
+                }
+                sticker::Content::Image(content) => {
+                    room.send(content).with_transaction_id(&txn_id).await?
+                }
+            })
+        })
+        .await?;
+        if args.print_ids {
+            println!("{room_id} {}", response.event_id);
+        }
+        event_ids.push(response.event_id);
+    } else if !mail.text.is_empty() || (mail.attachments.is_empty() && args.poll.is_none()) {
+        // An empty message is left out when there are attachments or a poll.
+        let recipient = template::Recipient {
+            room: room.display_name().await?.to_string(),
+            address: address.to_string(),
+            entry: mail.entry,
+        };
+        let text = mail.text.personalize(&recipient);
+        let parts = message::split(&text, args.split_size);
+        let threads_file = data_dir.join("threads");
+        let mut thread = match (&args.thread, args.thread_latest) {
+            (Some(root), _) => Some(root.clone()),
+            (None, true) => message::room_event(&threads_file, room_id).await?,
+            (None, false) => None,
+        };
+        let last_message_file = data_dir.join("last-messages");
+        let edit = match (&args.edit, args.edit_last) {
+            (Some(event_id), _) => Some(event_id.clone()),
+            (None, true) => Some(
+                message::room_event(&last_message_file, room_id)
+                    .await?
+                    .ok_or(format!("No message sent to {room_id} yet"))?,
+            ),
+            (None, false) => None,
+        };
+        if edit.is_some() && parts.len() > 1 {
+            return Err("The message is too large to replace another one".into());
+        }
+        // HTML can't be split, long messages are sent as plain text.
+        let html = mail.html.filter(|_| parts.len() == 1);
+        let html = match html {
+            Some(html) if !encrypted => Some(mail.inline.html(client, html).await?),
+            html => html.map(String::from),
+        };
+        for (i, part) in parts.iter().enumerate() {
+            let mut content = message::sized_content(part, html.as_deref(), args)?;
+            // Mention and reply only once.
+            if i == 0 && !args.mentions.is_empty() {
+                content = message::mention(content, &args.mentions);
+            }
+            if let (0, Some(event_id)) = (i, &args.in_reply_to) {
+                content = message::reply(&room, content, event_id).await?;
+            }
+            if let Some(root) = &thread {
+                content = message::in_thread(content, root);
+            }
+            if let Some(event_id) = &edit {
+                content = message::replacement(content, event_id);
+            }
+            let txn_id = in_flight.txn_id(room_id, "message", i);
+            let response = rate_limited(|| async {
+                Ok::<_, Box<dyn Error>>(match args.no_preview {
+                    true => {
+                        room.send_raw("m.room.message", message::without_previews(&content)?)
+                            .with_transaction_id(&txn_id)
+                            .await?
+                    }
+                    false => {
+                        room.send(content.clone())
+                            .with_transaction_id(&txn_id)
+                            .await?
+                    }
+                })
+            })
+            .await?;
+            if args.print_ids {
+                println!("{room_id} {}", response.event_id);
+            }
+            event_ids.push(response.event_id.clone());
+            // Without a thread to continue, this message starts the next one.
+            if args.thread_latest && thread.is_none() {
+                message::save_room_event(&threads_file, room_id, &response.event_id).await?;
+                thread = Some(response.event_id.clone());
+            }
+            // Edits keep replacing the original message.
+            if edit.is_none() {
+                message::save_room_event(&last_message_file, room_id, &response.event_id).await?;
+            }
+        }
+    }
+    if let Some(question) = &args.poll {
+        let content = message::poll(question, &args.options)?;
+        let txn_id = in_flight.txn_id(room_id, "poll", 0);
+        let response = rate_limited(|| async {
+            Ok::<_, Box<dyn Error>>(
+                room.send_raw(message::POLL_START, content.clone())
+                    .with_transaction_id(&txn_id)
+                    .await?,
+            )
+        })
+        .await?;
+        if args.print_ids {
+            println!("{room_id} {}", response.event_id);
+        }
+        event_ids.push(response.event_id);
+    }
+    // Images referred to from HTML can't be encrypted, encrypted rooms get them as attachments.
+    let inline = mail.inline.images().filter(|_| encrypted);
+    for (i, attachment) in mail.attachments.iter().chain(inline).enumerate() {
+        let txn_id = in_flight.txn_id(room_id, "attachment", i);
+        let event_id = rate_limited(|| attachment::send(&room, attachment, &txn_id)).await?;
+        if args.print_ids {
+            println!("{room_id} {event_id}");
+        }
+        event_ids.push(event_id);
+    }
+    Ok((room_id.to_owned(), event_ids))
 }
 
 fn prompt(message: &str) -> Result<String, io::Error> {
@@ -116,6 +1039,17 @@ fn prompt(message: &str) -> Result<String, io::Error> {
     Ok(String::from(buffer.strip_suffix("\n").unwrap_or(&buffer)))
 }
 
+// Use the given value or prompt for it.
+fn prompt_or(value: &Option<String>, message: &str, default: String) -> Result<String, io::Error> {
+    if let Some(value) = value {
+        return Ok(value.clone());
+    }
+    match prompt(&format!("{message} (default: {default}): "))? {
+        s if s.is_empty() => Ok(default),
+        s => Ok(s),
+    }
+}
+
 fn getpass(message: &str) -> Result<String, io::Error> {
     let stdin = io::stdin().as_raw_fd();
     let old_termios = termios::Termios::from_fd(stdin)?;
@@ -128,6 +1062,27 @@ fn getpass(message: &str) -> Result<String, io::Error> {
     pass
 }
 
+// Like getpass, but on the controlling terminal, because stdin is the message in send mode.
+fn getpass_tty(message: &str) -> Result<String, io::Error> {
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+    let old_termios = termios::Termios::from_fd(fd)?;
+    let mut new_termios = old_termios;
+    new_termios.c_lflag &= !termios::ECHO;
+    termios::tcsetattr(fd, termios::TCSAFLUSH, &new_termios)?;
+    tty.write_all(message.as_bytes())?;
+    tty.flush()?;
+    let mut buffer = String::new();
+    let pass = io::BufReader::new(&tty).read_line(&mut buffer);
+    termios::tcsetattr(fd, termios::TCSAFLUSH, &old_termios)?;
+    tty.write_all(b"\n")?;
+    pass?;
+    Ok(String::from(buffer.strip_suffix("\n").unwrap_or(&buffer)))
+}
+
 fn gethostname() -> Result<String, io::Error> {
     let mut buffer: Vec<u8> = Vec::with_capacity(libc::_SC_HOST_NAME_MAX.try_into().unwrap());
     #[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
@@ -135,145 +1090,1831 @@ fn gethostname() -> Result<String, io::Error> {
     #[cfg(not(any(target_arch = "aarch64", target_arch = "arm")))]
     let p = buffer.as_mut_ptr() as *mut i8;
     match unsafe { libc::gethostname(p, buffer.capacity()) } {
-        0 => Ok(String::from_utf8(buffer).unwrap()),
+        0 => {
+            // The name is NUL-terminated.
+            unsafe { buffer.set_len(libc::strlen(buffer.as_ptr() as *const libc::c_char)) };
+            Ok(String::from_utf8(buffer).unwrap())
+        }
         _ => Err(io::Error::last_os_error()),
     }
 }
 
-async fn login(store_path: &Path) -> Result<Client, Box<dyn Error>> {
-    let default_homeserver = String::from("matrix.org");
-    let homeserver = match prompt(&format!("Homeserver (default: {default_homeserver}): "))? {
-        s if s.is_empty() => default_homeserver,
-        s => s,
+// How to reach the homeserver, from the network options.
+#[derive(Default)]
+struct Network {
+    timeout: Option<Duration>,
+    proxy: Option<String>,
+    // Trusted besides the system's roots, or instead of them when pinned.
+    certificates: Vec<Certificate>,
+    pinned: bool,
+    insecure: bool,
+    tor: bool,
+}
+
+// The SOCKS port of a local Tor daemon, which resolves names too (socks5h).
+const TOR_PROXY: &str = "socks5h://127.0.0.1:9050";
+
+static NETWORK: OnceLock<Network> = OnceLock::new();
+
+// The options win over the configuration.
+fn set_network(args: &NetworkArgs) -> Result<(), Box<dyn Error>> {
+    let settings = config::get();
+    let ca_file = args.ca_file.as_ref().or(settings.ca_file.as_ref());
+    let pin_cert = args.pin_cert.as_ref().or(settings.pin_cert.as_ref());
+    let certificates = match pin_cert.or(ca_file) {
+        Some(file) => {
+            let pem = std::fs::read(file).map_err(|e| format!("{}: {e}", file.display()))?;
+            Certificate::from_pem_bundle(&pem).map_err(|e| format!("{}: {e}", file.display()))?
+        }
+        None => Vec::new(),
+    };
+    if args.insecure {
+        // Anyone on the way could read and change everything, including the access token.
+        eprintln!("WARNING: not verifying the homeserver's certificate (--insecure).");
+    }
+    let tor = args.tor || settings.tor.unwrap_or(false);
+    let proxy = args.proxy.clone().or(settings.proxy.clone());
+    let proxy = match tor {
+        true => Some(proxy.unwrap_or(String::from(TOR_PROXY))),
+        false => proxy,
     };
-    let homeserver = if homeserver.starts_with("https://") || homeserver.starts_with("http://") {
-        homeserver
-    } else {
-        format!("https://{homeserver}")
+    // With socks5:// the names would be looked up outside of Tor.
+    if tor && !through_tor(proxy.as_deref()) {
+        return Err("--tor needs a socks5h:// proxy, which looks up names through Tor".into());
+    }
+    let network = Network {
+        timeout: args.timeout.map(Duration::from_secs),
+        proxy,
+        certificates,
+        pinned: pin_cert.is_some(),
+        insecure: args.insecure,
+        tor,
     };
+    NETWORK.set(network).ok();
+    Ok(())
+}
+
+fn request_config() -> RequestConfig {
+    match NETWORK.get_or_init(Network::default).timeout {
+        Some(timeout) => RequestConfig::new().timeout(timeout),
+        None => RequestConfig::new(),
+    }
+}
 
-    let user = prompt("User: ")?;
+// matrix-sdk's builder can't add certificates, so it gets an HTTP client with all options.
+fn http_client() -> Result<reqwest::Client, reqwest::Error> {
+    let network = NETWORK.get_or_init(Network::default);
+    let mut builder = reqwest::Client::builder()
+        .user_agent(concat!("matrixmail/", env!("CARGO_PKG_VERSION")))
+        .tls_built_in_root_certs(!network.pinned)
+        .danger_accept_invalid_certs(network.insecure);
+    for certificate in &network.certificates {
+        builder = builder.add_root_certificate(certificate.clone());
+    }
+    // Without one, the proxy comes from HTTPS_PROXY or HTTP_PROXY, if set.
+    if let Some(proxy) = &network.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    builder.build()
+}
 
-    let password = getpass("Password: ")?;
+fn client_builder() -> Result<ClientBuilder, Box<dyn Error>> {
+    Ok(Client::builder()
+        .request_config(request_config())
+        .http_client(http_client()?))
+}
 
-    let default_device_name = gethostname().unwrap_or(String::from(""));
-    let device_name = match prompt(&format!("Device name (default: {default_device_name}): "))? {
-        s if s.is_empty() => default_device_name,
-        s => s,
+// Give up after the deadline wherever matrixmail is waiting, e.g. for a homeserver that hangs.
+// The messages sent so far are still in flight, so running matrixmail again won't send them twice.
+fn start_deadline(deadline: Option<u64>) {
+    let Some(deadline) = deadline else {
+        return;
     };
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(deadline)).await;
+        eprintln!("Deadline of {deadline} seconds exceeded.");
+        process::exit(sysexits::EX_TEMPFAIL);
+    });
+}
+
+fn through_tor(proxy: Option<&str>) -> bool {
+    proxy.is_some_and(|proxy| proxy.starts_with("socks5h://"))
+}
+
+// Onion services can only be reached through Tor, and looking up their names anywhere else
+// gives them away.
+fn check_onion(url: &Url) -> Result<(), Box<dyn Error>> {
+    let onion = url.host_str().is_some_and(|host| host.ends_with(".onion"));
+    let proxy = NETWORK.get_or_init(Network::default).proxy.as_deref();
+    if onion && !through_tor(proxy) {
+        return Err("The homeserver is an onion service, use --tor or a socks5h:// proxy".into());
+    }
+    Ok(())
+}
+
+// URLs are used as given, server names are resolved with /.well-known/matrix/client. Through
+// Tor they aren't, the answer could point off Tor. Onion services are encrypted by Tor already.
+fn homeserver_builder(homeserver: &str) -> Result<ClientBuilder, Box<dyn Error>> {
+    let builder = client_builder()?;
+    if homeserver.starts_with("https://") || homeserver.starts_with("http://") {
+        let url = Url::parse(homeserver)?;
+        check_onion(&url)?;
+        return Ok(builder.homeserver_url(url));
+    }
+    let server_name = ServerName::parse(homeserver)?;
+    let onion = server_name.host().ends_with(".onion");
+    if onion || NETWORK.get_or_init(Network::default).tor {
+        let scheme = if onion { "http" } else { "https" };
+        let url = Url::parse(&format!("{scheme}://{server_name}"))?;
+        check_onion(&url)?;
+        return Ok(builder.homeserver_url(url));
+    }
+    Ok(builder.server_name(&server_name))
+}
 
-    let default_display_name = format!("{user}@{device_name}", user = env::var("USER").unwrap());
-    let display_name = match prompt(&format!("Display name (default: {default_display_name}): "))? {
-        s if s.is_empty() => default_display_name,
-        s => s,
+// The descriptor is closed after reading, so it must be an open one that was handed over for
+// the password, not standard input, output or error.
+fn parse_password_fd(s: &str) -> Result<RawFd, String> {
+    let fd: RawFd = s
+        .parse()
+        .map_err(|_| format!("invalid file descriptor {s}"))?;
+    if fd < 3 {
+        return Err(String::from(
+            "standard input, output and error can't be used",
+        ));
+    }
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(format!("file descriptor {fd} isn't open"));
+    }
+    Ok(fd)
+}
+
+async fn password(args: &LoginArgs) -> Result<String, io::Error> {
+    if let Some(password_file) = &args.password_file {
+        let password = fs::read_to_string(password_file).await?;
+        return Ok(String::from(
+            password.strip_suffix("\n").unwrap_or(&password),
+        ));
+    }
+    if let Some(password_fd) = args.password_fd {
+        // The descriptor is handed over by the caller and closed after reading.
+        let mut password = String::new();
+        File::from_std(unsafe { std::fs::File::from_raw_fd(password_fd) })
+            .read_to_string(&mut password)
+            .await?;
+        return Ok(String::from(
+            password.strip_suffix("\n").unwrap_or(&password),
+        ));
+    }
+    if let Some(password) = credential("matrixmail.password").await? {
+        return Ok(password);
+    }
+    if let Ok(password) = env::var("MATRIXMAIL_PASSWORD") {
+        return Ok(password);
+    }
+    getpass("Password: ")
+}
+
+// Read a systemd credential (LoadCredential=), if the service was given one by that name.
+async fn credential(name: &str) -> Result<Option<String>, io::Error> {
+    let Some(directory) = env::var_os("CREDENTIALS_DIRECTORY") else {
+        return Ok(None);
     };
+    match fs::read_to_string(Path::new(&directory).join(name)).await {
+        Ok(value) => Ok(Some(String::from(
+            value.strip_suffix("\n").unwrap_or(&value),
+        ))),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+// Like user@host, with the local login name, which isn't set in containers and the like.
+fn default_display_name(user: &str, device_name: &str) -> String {
+    let localpart = user
+        .trim_start_matches('@')
+        .split(':')
+        .next()
+        .unwrap_or_default();
+    let user = match env::var("USER") {
+        Ok(user) if !user.is_empty() => user,
+        _ if !localpart.is_empty() => String::from(localpart),
+        _ => String::from("matrixmail"),
+    };
+    format!("{user}@{device_name}")
+}
+
+// Log in, prompting for everything not given in the arguments.
+async fn login(store_path: &Path, args: &LoginArgs) -> Result<Client, Box<dyn Error>> {
+    let homeserver = args.homeserver.clone().or(config::get().homeserver.clone());
+    let homeserver = prompt_or(&homeserver, "Homeserver", String::from("matrix.org"))?;
 
-    let client = Client::builder()
-        .homeserver_url(Url::parse(&homeserver)?)
-        .sqlite_store(&store_path, None)
+    let client = homeserver_builder(&homeserver)?
+        .sqlite_store(store_path, None)
         .build()
         .await?;
-    let _response = client
-        .matrix_auth()
-        .login_username(&user, &password)
-        .initial_device_display_name(&display_name)
-        .device_id(&device_name)
+    // Homeservers using next-generation auth delegate the login to their OIDC provider.
+    let issuer = oidc::issuer(&client);
+    if args.other_device && issuer.is_none() {
+        return Err("Logging in from another device needs a homeserver with OIDC login".into());
+    }
+    let login_types = match issuer {
+        Some(_) => Vec::new(),
+        None => client.matrix_auth().get_login_types().await?.flows,
+    };
+    let supports_password = login_types
+        .iter()
+        .any(|login_type| matches!(login_type, LoginType::Password(_)));
+    let supports_sso = login_types
+        .iter()
+        .any(|login_type| matches!(login_type, LoginType::Sso(_)));
+
+    // Homeservers that support both let the user choose SSO by leaving the user empty.
+    let user = match (issuer.is_some(), supports_password, supports_sso) {
+        (false, true, _) if args.user.is_some() => args.user.clone().unwrap(),
+        (true, _, _) => String::new(),
+        (false, true, true) => prompt("User (leave empty for SSO): ")?,
+        (false, true, false) => prompt("User: ")?,
+        (false, false, true) => String::new(),
+        (false, false, false) => {
+            return Err("Homeserver supports neither password nor SSO login".into())
+        }
+    };
+
+    let password = match user.is_empty() {
+        true => None,
+        false => Some(password(args).await?),
+    };
+
+    let default_device_name = gethostname().unwrap_or(String::from(""));
+    let device_name = prompt_or(&args.device_name, "Device name", default_device_name)?;
+
+    let display_name = match &args.display_name {
+        Some(display_name) => display_name.clone(),
+        None => prompt_or(
+            &None,
+            "Display name",
+            default_display_name(&user, &device_name),
+        )?,
+    };
+
+    match (issuer, password) {
+        (Some(issuer), _) => {
+            match args.other_device {
+                true => oidc::login_on_other_device(&client, issuer, &device_name).await?,
+                false => oidc::login(&client, issuer, &device_name).await?,
+            }
+            // The device display name can't be passed with the OIDC login.
+            let device_id = client.device_id().unwrap().to_owned();
+            client.rename_device(&device_id, &display_name).await?;
+        }
+        (None, Some(password)) => {
+            let _response = client
+                .matrix_auth()
+                .login_username(&user, &password)
+                .initial_device_display_name(&display_name)
+                .device_id(&device_name)
+                .request_refresh_token()
+                .await?;
+        }
+        (None, None) => {
+            // The SDK serves the redirect target on localhost and waits for the login token.
+            let _response = client
+                .matrix_auth()
+                .login_sso(|sso_url| async move {
+                    println!("Open this URL in a browser to log in:\n{sso_url}");
+                    Ok(())
+                })
+                .initial_device_display_name(&display_name)
+                .device_id(&device_name)
+                .request_refresh_token()
+                .await?;
+        }
+    }
+
+    Ok(client)
+}
+
+// A session exported by another client or SDK tool, in either naming convention.
+#[derive(Deserialize)]
+struct ImportedSession {
+    #[serde(
+        default,
+        alias = "homeserver_url",
+        alias = "homeserverUrl",
+        alias = "base_url",
+        alias = "baseUrl"
+    )]
+    homeserver: Option<String>,
+    #[serde(alias = "userId")]
+    user_id: OwnedUserId,
+    #[serde(alias = "deviceId")]
+    device_id: OwnedDeviceId,
+    #[serde(alias = "accessToken")]
+    access_token: String,
+}
+
+async fn import_session(
+    store_path: &Path,
+    homeserver: Option<&str>,
+    file: &Path,
+) -> Result<Client, Box<dyn Error>> {
+    let imported: ImportedSession = serde_json::from_str(&fs::read_to_string(file).await?)?;
+    // Without a homeserver, look it up from the user's server name.
+    let homeserver = match (homeserver, imported.homeserver) {
+        (Some(homeserver), _) => homeserver.to_owned(),
+        (None, Some(homeserver)) => homeserver,
+        (None, None) => imported.user_id.server_name().to_string(),
+    };
+    login_with_token(
+        store_path,
+        &homeserver,
+        imported.user_id,
+        imported.device_id,
+        imported.access_token,
+    )
+    .await
+}
+
+// Restore a session from an access token obtained elsewhere, checking that it is valid.
+async fn login_with_token(
+    store_path: &Path,
+    homeserver: &str,
+    user_id: OwnedUserId,
+    device_id: OwnedDeviceId,
+    access_token: String,
+) -> Result<Client, Box<dyn Error>> {
+    let client = homeserver_builder(homeserver)?
+        .sqlite_store(store_path, None)
+        .build()
         .await?;
+    let auth_session = MatrixSession {
+        meta: SessionMeta {
+            user_id: user_id.clone(),
+            device_id,
+        },
+        tokens: MatrixSessionTokens {
+            access_token,
+            refresh_token: None,
+        },
+    };
+    client.restore_session(auth_session).await?;
+    let response = client.send(whoami::v3::Request::new(), None).await?;
+    if response.user_id != user_id {
+        return Err(format!("Access token belongs to {}", response.user_id).into());
+    }
+    Ok(client)
+}
 
+fn new_session(client: &Client) -> Session {
+    match client.oidc().full_session() {
+        Some(full_session) => Session {
+            homeserver: client.homeserver().to_string(),
+            user_id: full_session.user.meta.user_id.clone(),
+            device_id: full_session.user.meta.device_id.clone(),
+            access_token: full_session.user.tokens.access_token.clone(),
+            refresh_token: full_session.user.tokens.refresh_token.clone(),
+            sync_token: None,
+            oidc: Some(oidc::OidcRegistration::from(&full_session)),
+            keyring: false,
+            passphrase: None,
+            password_file: None,
+            appservice: None,
+        },
+        None => {
+            let auth_session = client.matrix_auth().session().unwrap();
+            Session {
+                homeserver: client.homeserver().to_string(),
+                user_id: auth_session.meta.user_id,
+                device_id: auth_session.meta.device_id,
+                access_token: auth_session.tokens.access_token,
+                refresh_token: auth_session.tokens.refresh_token,
+                sync_token: None,
+                oidc: None,
+                keyring: false,
+                passphrase: None,
+                password_file: None,
+                appservice: None,
+            }
+        }
+    }
+}
+
+async fn restore_client(store_path: &Path, session: &Session) -> Result<Client, Box<dyn Error>> {
+    // Refresh expired access tokens, if the homeserver issued a refresh token.
+    let homeserver = Url::parse(&session.homeserver)?;
+    check_onion(&homeserver)?;
+    let client = client_builder()?
+        .homeserver_url(homeserver)
+        .sqlite_store(store_path, None)
+        .handle_refresh_tokens()
+        .build()
+        .await?;
+    let meta = SessionMeta {
+        user_id: session.user_id.clone(),
+        device_id: session.device_id.clone(),
+    };
+    match &session.oidc {
+        Some(registration) => {
+            oidc::restore_session(
+                &client,
+                registration,
+                meta,
+                session.access_token.clone(),
+                session.refresh_token.clone(),
+            )
+            .await?
+        }
+        None => {
+            let auth_session = MatrixSession {
+                meta,
+                tokens: MatrixSessionTokens {
+                    access_token: session.access_token.clone(),
+                    refresh_token: session.refresh_token.clone(),
+                },
+            };
+            client.restore_session(auth_session).await?;
+        }
+    }
     Ok(client)
 }
 
+// Copy the possibly refreshed tokens back into the session before saving it.
+fn update_session_tokens(client: &Client, session: &mut Session) {
+    match &session.oidc {
+        Some(_) => {
+            let tokens = client.oidc().session_tokens().unwrap();
+            session.access_token = tokens.access_token;
+            session.refresh_token = tokens.refresh_token;
+        }
+        None => {
+            let auth_session = client.matrix_auth().session().unwrap();
+            session.access_token = auth_session.tokens.access_token.clone();
+            session.refresh_token = auth_session.tokens.refresh_token.clone();
+        }
+    }
+}
+
+// Save the session as soon as the tokens are refreshed.
+// Refresh tokens can only be used once, losing the new one would end the session.
+fn persist_session_tokens(client: &Client, session_file: &Path, session: &Session) {
+    let session_file = session_file.to_path_buf();
+    let mut session = session.clone();
+    match &session.oidc {
+        Some(_) => {
+            let Some(mut tokens_stream) = client.oidc().session_tokens_stream() else {
+                return;
+            };
+            tokio::spawn(async move {
+                while let Some(tokens) = tokens_stream.next().await {
+                    session.access_token = tokens.access_token;
+                    session.refresh_token = tokens.refresh_token;
+                    if let Err(e) = save_session(&session_file, &session).await {
+                        eprintln!("Error saving session: {e}");
+                    }
+                }
+            });
+        }
+        None => {
+            let Some(mut tokens_stream) = client.matrix_auth().session_tokens_stream() else {
+                return;
+            };
+            tokio::spawn(async move {
+                while let Some(tokens) = tokens_stream.next().await {
+                    session.access_token = tokens.access_token;
+                    session.refresh_token = tokens.refresh_token;
+                    if let Err(e) = save_session(&session_file, &session).await {
+                        eprintln!("Error saving session: {e}");
+                    }
+                }
+            });
+        }
+    }
+}
+
+// Returns whether it was a soft logout if the access token is not valid anymore.
+fn unknown_token(error: &matrix_sdk::Error) -> Option<bool> {
+    match error.client_api_error_kind() {
+        Some(ErrorKind::UnknownToken { soft_logout }) => Some(*soft_logout),
+        _ => None,
+    }
+}
+
+// Whether the homeserver couldn't be reached at all, as opposed to refusing the request.
+fn unreachable(error: &(dyn Error + 'static)) -> bool {
+    match error.downcast_ref::<matrix_sdk::Error>() {
+        Some(matrix_sdk::Error::Http(matrix_sdk::HttpError::Reqwest(e))) => {
+            e.is_connect() || e.is_timeout()
+        }
+        _ => false,
+    }
+}
+
+// Set by -v.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// A step of the delivery, with -v, like mailx -v shows the SMTP dialogue.
+fn trace(message: impl Display) {
+    if VERBOSE.load(Ordering::Relaxed) {
+        eprintln!("{message}");
+    }
+}
+
+// How long sending to the address took and which events were sent.
+async fn traced<F>(address: &Address, send: F) -> F::Output
+where
+    F: Future<Output = Result<(OwnedRoomId, Vec<OwnedEventId>), Box<dyn Error>>>,
+{
+    let start = Instant::now();
+    trace(format_args!("Sending to {address}"));
+    let result = send.await;
+    let elapsed = start.elapsed();
+    match &result {
+        Ok((room_id, event_ids)) => {
+            let event_ids: Vec<String> = event_ids.iter().map(ToString::to_string).collect();
+            trace(format_args!(
+                "Sent to {address} ({room_id}) in {elapsed:.1?}: {}",
+                event_ids.join(" ")
+            ));
+        }
+        Err(e) => trace(format_args!(
+            "Sending to {address} failed after {elapsed:.1?}: {e}"
+        )),
+    }
+    result
+}
+
+// How often to send a request while the homeserver is rate limiting.
+const RATE_LIMIT_ATTEMPTS: u32 = 5;
+
+// How long a continuous sync waits for new events, in seconds.
+const SYNC_TIMEOUT: u64 = 30;
+
+// How long the homeserver asks to wait, if the request was rate limited.
+fn retry_after(error: &(dyn Error + 'static)) -> Option<Duration> {
+    let kind = match error.downcast_ref::<matrix_sdk::Error>() {
+        Some(error) => error.client_api_error_kind(),
+        None => error
+            .downcast_ref::<matrix_sdk::HttpError>()?
+            .client_api_error_kind(),
+    };
+    match kind {
+        Some(ErrorKind::LimitExceeded { retry_after_ms }) => {
+            Some(retry_after_ms.unwrap_or(Duration::from_secs(1)))
+        }
+        _ => None,
+    }
+}
+
+// Send the request again while it is rate limited, waiting as long as the homeserver asks,
+// so that a burst of messages gets through instead of failing.
+async fn rate_limited<T, F, Fut>(mut request: F) -> Result<T, Box<dyn Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error>>>,
+{
+    let attempts = config::get().retry_attempts.unwrap_or(RATE_LIMIT_ATTEMPTS);
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(e) if attempt < attempts => match retry_after(&*e) {
+                Some(delay) => {
+                    trace(format_args!("Rate limited, trying again in {delay:.1?}"));
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                None => return Err(e),
+            },
+            result => return result,
+        }
+    }
+}
+
+// Sync once, retrying when rate limited.
+async fn sync_once(
+    client: &Client,
+    settings: &SyncSettings,
+) -> Result<SyncResponse, Box<dyn Error>> {
+    rate_limited(|| async { Ok::<_, Box<dyn Error>>(client.sync_once(settings.clone()).await?) })
+        .await
+}
+
+// The password from the credential source given at login.
+async fn stored_password(session: &Session) -> Result<Option<String>, io::Error> {
+    if let Some(password_file) = &session.password_file {
+        let password = fs::read_to_string(password_file).await?;
+        return Ok(Some(String::from(
+            password.strip_suffix("\n").unwrap_or(&password),
+        )));
+    }
+    if let Some(password) = credential("matrixmail.password").await? {
+        return Ok(Some(password));
+    }
+    Ok(env::var("MATRIXMAIL_PASSWORD").ok())
+}
+
+// The access token was revoked or expired and couldn't be refreshed.
+// After a soft logout the device and its keys are kept, so log in again with the stored
+// credentials, or ask for the password if there is someone to ask. Otherwise the device is
+// gone, and logging in again in place would keep using its encryption keys.
+async fn session_expired(
+    client: &Client,
+    session: &Session,
+    soft_logout: bool,
+) -> Result<(), Box<dyn Error>> {
+    if soft_logout && session.oidc.is_none() {
+        let interactive = unsafe { libc::isatty(libc::STDERR_FILENO) } == 1;
+        let password = match stored_password(session).await? {
+            Some(password) => Some(password),
+            None if interactive => {
+                eprintln!("Session expired.");
+                Some(getpass_tty(&format!("Password for {}: ", session.user_id))?)
+            }
+            None => None,
+        };
+        if let Some(password) = password {
+            client
+                .matrix_auth()
+                .login_username(&session.user_id, &password)
+                .device_id(session.device_id.as_str())
+                .request_refresh_token()
+                .await?;
+            return Ok(());
+        }
+    }
+    eprintln!("Session expired, run `matrixmail login` to log in again.");
+    process::exit(EXIT_SESSION_EXPIRED);
+}
+
+async fn login_command(
+    data_dir: &Path,
+    session_file: &Path,
+    mut args: LoginArgs,
+) -> Result<(), Box<dyn Error>> {
+    // Without --access-token, the token for --device-id comes from a systemd credential.
+    let access_token = match (args.access_token.take(), &args.device_id) {
+        (Some(access_token), _) => Some(access_token),
+        (None, Some(_)) => {
+            let access_token = credential("matrixmail.access-token").await?;
+            Some(access_token.ok_or("--device-id needs --access-token or a credential")?)
+        }
+        (None, None) => None,
+    };
+    let client = match (access_token, &args.appservice, &args.import) {
+        (_, _, Some(import)) => {
+            import_session(data_dir, args.homeserver.as_deref(), import).await?
+        }
+        (Some(access_token), _, None) => {
+            let user_id = OwnedUserId::try_from(args.user.unwrap())?;
+            login_with_token(
+                data_dir,
+                &args.homeserver.unwrap(),
+                user_id,
+                args.device_id.unwrap(),
+                access_token,
+            )
+            .await?
+        }
+        (None, Some(registration), None) => {
+            let user_id = OwnedUserId::try_from(args.user.unwrap())?;
+            login_with_token(
+                data_dir,
+                &args.homeserver.unwrap(),
+                user_id,
+                OwnedDeviceId::from(APPSERVICE_DEVICE_ID),
+                appservice_token(registration).await?,
+            )
+            .await?
+        }
+        (None, None, None) => login(data_dir, &args).await?,
+    };
+    save_new_session(
+        &client,
+        session_file,
+        args.keyring,
+        args.encrypt,
+        args.password_file.as_deref(),
+        args.appservice.as_deref(),
+    )
+    .await
+}
+
+async fn register_command(
+    data_dir: &Path,
+    session_file: &Path,
+    args: RegisterArgs,
+) -> Result<(), Box<dyn Error>> {
+    let homeserver = args.homeserver.clone().or(config::get().homeserver.clone());
+    let homeserver = prompt_or(&homeserver, "Homeserver", String::from("matrix.org"))?;
+    let client = homeserver_builder(&homeserver)?
+        .sqlite_store(data_dir, None)
+        .build()
+        .await?;
+
+    let user = match &args.user {
+        Some(user) => user.clone(),
+        None => prompt("User: ")?,
+    };
+    let password = match (&args.password_file, env::var("MATRIXMAIL_PASSWORD")) {
+        (Some(password_file), _) => {
+            let password = fs::read_to_string(password_file).await?;
+            String::from(password.strip_suffix("\n").unwrap_or(&password))
+        }
+        (None, Ok(password)) => password,
+        (None, Err(_)) => register::new_password()?,
+    };
+
+    let default_device_name = gethostname().unwrap_or(String::from(""));
+    let device_name = prompt_or(&args.device_name, "Device name", default_device_name)?;
+
+    let display_name = match &args.display_name {
+        Some(display_name) => display_name.clone(),
+        None => prompt_or(
+            &None,
+            "Display name",
+            default_display_name(&user, &device_name),
+        )?,
+    };
+
+    register::register(
+        &client,
+        &user,
+        &password,
+        &device_name,
+        &display_name,
+        args.registration_token.as_deref(),
+    )
+    .await?;
+    save_new_session(
+        &client,
+        session_file,
+        args.keyring,
+        args.encrypt,
+        args.password_file.as_deref(),
+        None,
+    )
+    .await
+}
+
+// Save the session of a freshly logged in client.
+async fn save_new_session(
+    client: &Client,
+    session_file: &Path,
+    keyring: bool,
+    encrypt: bool,
+    password_file: Option<&Path>,
+    appservice: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let mut session = new_session(client);
+    session.keyring = keyring;
+    // Cron jobs don't run in the same working directory.
+    session.password_file = match password_file {
+        Some(password_file) => Some(fs::canonicalize(password_file).await?),
+        None => None,
+    };
+    session.appservice = match appservice {
+        Some(registration) => Some(fs::canonicalize(registration).await?),
+        None => None,
+    };
+    // Upload the device keys right away, so that other devices know this one before it sends
+    // its first encrypted message. Application service users have no device to upload keys for.
+    if session.appservice.is_none() {
+        let filter = FilterDefinition::with_lazy_loading();
+        let response = client
+            .sync_once(SyncSettings::default().filter(filter.into()))
+            .await?;
+        session.sync_token = Some(response.next_batch);
+    }
+    if encrypt {
+        session.passphrase = Some(session_passphrase(true)?);
+    }
+    save_session(session_file, &session)
+        .await
+        .map_err(|e| sysexits::context(e, "Error saving session"))
+}
+
+// Invalidate the access token and remove everything that belongs to the session.
+async fn logout(data_dir: &Path, session_file: &Path) -> Result<(), Box<dyn Error>> {
+    let (client, session) = open_session(data_dir, session_file).await?;
+    let result: Result<(), Box<dyn Error>> = match session.oidc {
+        Some(_) => client.oidc().logout().await.map(|_| ()).map_err(Box::from),
+        None => client
+            .matrix_auth()
+            .logout()
+            .await
+            .map(|_| ())
+            .map_err(Box::from),
+    };
+    // The token might already be invalid, clean up anyway.
+    if let Err(e) = result {
+        eprintln!("Error logging out: {e}");
+    }
+    drop(client);
+
+    if session.keyring {
+        keyring_entry(&session)?.delete_password()?;
+    }
+    fs::remove_file(session_file).await?;
+    let mut entries = fs::read_dir(data_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with("matrix-sdk-")
+        {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+// Restore the saved session for a subcommand.
+async fn open_session(
+    data_dir: &Path,
+    session_file: &Path,
+) -> Result<(Client, Session), Box<dyn Error>> {
+    // Most likely not logged in yet.
+    let session = load_session(session_file).await.map_err(|e| {
+        sysexits::fail(
+            sysexits::EX_UNAVAILABLE,
+            format_args!("Error loading session: {e}"),
+        )
+    })?;
+    let client = restore_client(data_dir, &session)
+        .await
+        .map_err(|e| sysexits::context(e, "Error restoring session"))?;
+    persist_session_tokens(&client, session_file, &session);
+    Ok((client, session))
+}
+
+async fn close_session(
+    client: &Client,
+    session_file: &Path,
+    mut session: Session,
+) -> Result<(), Box<dyn Error>> {
+    update_session_tokens(client, &mut session);
+    save_session(session_file, &session)
+        .await
+        .map_err(|e| sysexits::context(e, "Error saving session"))
+}
+
+async fn whoami(data_dir: &Path, session_file: &Path) -> Result<(), Box<dyn Error>> {
+    let (client, session) = open_session(data_dir, session_file).await?;
+    println!("User ID: {}", session.user_id);
+    println!("Device ID: {}", session.device_id);
+    println!("Homeserver: {}", session.homeserver);
+    let valid = match client.send(whoami::v3::Request::new(), None).await {
+        Ok(response) if response.user_id != session.user_id => {
+            println!("Token: valid for {}", response.user_id);
+            false
+        }
+        Ok(_) => {
+            println!("Token: valid");
+            true
+        }
+        Err(e) => {
+            println!("Token: invalid ({e})");
+            false
+        }
+    };
+    close_session(&client, session_file, session).await?;
+    if !valid {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+async fn devices_command(
+    data_dir: &Path,
+    session_file: &Path,
+    command: DevicesCommand,
+) -> Result<(), Box<dyn Error>> {
+    let (client, session) = open_session(data_dir, session_file).await?;
+    match command {
+        DevicesCommand::List => devices::list(&client).await?,
+        DevicesCommand::Rename {
+            device_id,
+            display_name,
+        } => devices::rename(&client, &device_id, &display_name).await?,
+        DevicesCommand::Delete { device_ids } => devices::delete(&client, &device_ids).await?,
+    }
+    close_session(&client, session_file, session).await
+}
+
+async fn invites_command(
+    data_dir: &Path,
+    session_file: &Path,
+    command: InvitesCommand,
+) -> Result<(), Box<dyn Error>> {
+    let (client, mut session) = open_session(data_dir, session_file).await?;
+    let response = sync_once(&client, &sync_settings(&session)).await?;
+    session.sync_token = Some(response.next_batch);
+    match command {
+        InvitesCommand::List => invites::list(&client).await?,
+        InvitesCommand::Accept { room_ids } => invites::accept(&client, &room_ids).await?,
+        InvitesCommand::Reject { room_ids } => invites::reject(&client, &room_ids).await?,
+    }
+    close_session(&client, session_file, session).await
+}
+
+async fn crypto_command(
+    data_dir: &Path,
+    session_file: &Path,
+    command: CryptoCommand,
+) -> Result<(), Box<dyn Error>> {
+    let (client, session) = open_session(data_dir, session_file).await?;
+    match command {
+        CryptoCommand::Bootstrap { reset } => crypto::bootstrap(&client, reset).await?,
+        CryptoCommand::Unlock => crypto::unlock(&client).await?,
+        CryptoCommand::RestoreBackup => crypto::restore_backup(&client).await?,
+        CryptoCommand::ExportKeys { file } => crypto::export_keys(&client, &file).await?,
+        CryptoCommand::ImportKeys { file } => crypto::import_keys(&client, &file).await?,
+        CryptoCommand::ServeKeys { seconds } => {
+            crypto::serve_keys(&client, Duration::from_secs(seconds)).await?
+        }
+    }
+    close_session(&client, session_file, session).await
+}
+
+// With check, like mailx -e, only the exit status tells whether there are unread messages.
+async fn read_command(
+    data_dir: &Path,
+    session_file: &Path,
+    check: bool,
+) -> Result<(), Box<dyn Error>> {
+    let (client, mut session) = open_session(data_dir, session_file).await?;
+    let response = sync_once(&client, &sync_settings(&session)).await?;
+    session.sync_token = Some(response.next_batch);
+    let mut headers = inbox::headers(&client).await?;
+    if check {
+        close_session(&client, session_file, session).await?;
+        process::exit(if headers.is_empty() { 1 } else { 0 });
+    }
+    inbox::print_headers(&headers);
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 && !headers.is_empty() {
+        inbox::interact(&mut headers).await?;
+    }
+    close_session(&client, session_file, session).await
+}
+
+async fn export_command(
+    data_dir: &Path,
+    session_file: &Path,
+    address: &Address,
+    since: Option<i64>,
+    format: export::Format,
+    file: &Path,
+    media_dir: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let (client, session) = open_session(data_dir, session_file).await?;
+    let room = address::room(&client, address, data_dir).await?;
+    if let Some(dir) = media_dir {
+        fs::create_dir_all(dir).await?;
+    }
+    export::export(&client, &room, since, format, file, media_dir).await?;
+    close_session(&client, session_file, session).await
+}
+
+async fn maildir_command(
+    data_dir: &Path,
+    session_file: &Path,
+    dir: &Path,
+    rules_file: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let rules_file = rules_file.or(Some(config_dir().join("rules.yaml")).filter(|f| f.exists()));
+    let rules = match rules_file {
+        Some(file) => rules::load(&file).await?,
+        None => Vec::new(),
+    };
+    let (client, session) = open_session(data_dir, session_file).await?;
+    maildir::watch(&client, dir, rules);
+    sync_forever(&client, session_file, session).await
+}
+
+async fn tail_command(
+    data_dir: &Path,
+    session_file: &Path,
+    addresses: &[Address],
+    format: tail::Format,
+) -> Result<(), Box<dyn Error>> {
+    let (client, mut session) = open_session(data_dir, session_file).await?;
+    let mut room_ids = Vec::new();
+    for address in addresses {
+        room_ids.push(
+            address::room(&client, address, data_dir)
+                .await?
+                .room_id()
+                .to_owned(),
+        );
+    }
+    // Skip the messages that arrived while not running.
+    let response = sync_once(&client, &sync_settings(&session)).await?;
+    session.sync_token = Some(response.next_batch);
+    tail::watch(&client, room_ids, format);
+    sync_forever(&client, session_file, session).await
+}
+
+async fn rooms_command(
+    data_dir: &Path,
+    session_file: &Path,
+    format: rooms::Format,
+) -> Result<(), Box<dyn Error>> {
+    let (client, mut session) = open_session(data_dir, session_file).await?;
+    let response = sync_once(&client, &sync_settings(&session)).await?;
+    session.sync_token = Some(response.next_batch);
+    rooms::list(&client, format).await?;
+    close_session(&client, session_file, session).await
+}
+
+async fn leave_command(
+    data_dir: &Path,
+    session_file: &Path,
+    address: &Address,
+    forget: bool,
+) -> Result<(), Box<dyn Error>> {
+    let (client, mut session) = open_session(data_dir, session_file).await?;
+    let response = sync_once(&client, &sync_settings(&session)).await?;
+    session.sync_token = Some(response.next_batch);
+    let room = address::find_room(&client, address)
+        .await?
+        .ok_or(format!("Not in a room for {address}"))?;
+    if room.state() != RoomState::Left {
+        room.leave().await?;
+    }
+    // Forgetting also removes the room from the store.
+    if forget {
+        room.forget().await?;
+    }
+    close_session(&client, session_file, session).await
+}
+
+async fn room_info_command(
+    data_dir: &Path,
+    session_file: &Path,
+    address: &Address,
+) -> Result<(), Box<dyn Error>> {
+    let (client, mut session) = open_session(data_dir, session_file).await?;
+    let response = sync_once(&client, &sync_settings(&session)).await?;
+    session.sync_token = Some(response.next_batch);
+    let room = address::find_room(&client, address)
+        .await?
+        .ok_or(format!("Not in a room for {address}"))?;
+    rooms::info(&client, &room).await?;
+    close_session(&client, session_file, session).await
+}
+
+// Like grep, the exit status is 1 when nothing was found.
+async fn search_command(
+    data_dir: &Path,
+    session_file: &Path,
+    pattern: &str,
+    addresses: &[Address],
+    since: Option<i64>,
+) -> Result<(), Box<dyn Error>> {
+    let (client, mut session) = open_session(data_dir, session_file).await?;
+    let response = sync_once(&client, &sync_settings(&session)).await?;
+    session.sync_token = Some(response.next_batch);
+    let mut rooms = Vec::new();
+    for address in addresses {
+        rooms.push(address::room(&client, address, data_dir).await?);
+    }
+    if rooms.is_empty() {
+        rooms = client.joined_rooms();
+    }
+    let found = search::search(&client, rooms, pattern, since).await?;
+    close_session(&client, session_file, session).await?;
+    if !found {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn sync_settings(session: &Session) -> SyncSettings {
+    let filter = FilterDefinition::with_lazy_loading();
+    let settings = SyncSettings::default().filter(filter.into());
+    match session.sync_token.clone() {
+        Some(sync_token) => settings.token(sync_token),
+        None => settings,
+    }
+}
+
+// Keep syncing for the event handlers. The sync token is saved after every sync, so that a
+// restart continues where it stopped.
+async fn sync_forever(
+    client: &Client,
+    session_file: &Path,
+    mut session: Session,
+) -> Result<(), Box<dyn Error>> {
+    let timeout = config::get().sync_timeout.unwrap_or(SYNC_TIMEOUT);
+    let mut settings = sync_settings(&session).timeout(Duration::from_secs(timeout));
+    loop {
+        let response = sync_once(client, &settings).await?;
+        settings = settings.token(response.next_batch.clone());
+        session.sync_token = Some(response.next_batch);
+        update_session_tokens(client, &mut session);
+        save_session(session_file, &session).await?;
+    }
+}
+
+async fn reap_command(data_dir: &Path, session_file: &Path) -> Result<(), Box<dyn Error>> {
+    let (client, session) = open_session(data_dir, session_file).await?;
+    expire::reap(&client, data_dir).await?;
+    close_session(&client, session_file, session).await
+}
+
+async fn react_command(
+    data_dir: &Path,
+    session_file: &Path,
+    address: &Address,
+    event_id: OwnedEventId,
+    key: String,
+) -> Result<(), Box<dyn Error>> {
+    let (client, session) = open_session(data_dir, session_file).await?;
+    let room = address::room(&client, address, data_dir).await?;
+    prepare_room(&room).await?;
+    let content = ReactionEventContent::new(Annotation::new(event_id, key));
+    rate_limited(|| async { Ok::<_, Box<dyn Error>>(room.send(content.clone()).await?) }).await?;
+    close_session(&client, session_file, session).await
+}
+
+async fn mark_read_command(
+    data_dir: &Path,
+    session_file: &Path,
+    address: &Address,
+    event_id: Option<OwnedEventId>,
+) -> Result<(), Box<dyn Error>> {
+    let (client, session) = open_session(data_dir, session_file).await?;
+    let room = address::room(&client, address, data_dir).await?;
+    let event_id = match event_id {
+        Some(event_id) => Some(event_id),
+        None => inbox::last_event(&room).await?,
+    };
+    if let Some(event_id) = event_id {
+        rate_limited(|| async {
+            Ok::<_, Box<dyn Error>>(inbox::mark_read(&room, &event_id).await?)
+        })
+        .await?;
+    }
+    close_session(&client, session_file, session).await
+}
+
+async fn forward_command(
+    data_dir: &Path,
+    session_file: &Path,
+    address: &Address,
+    event_id: &EventId,
+    addresses: &[Address],
+) -> Result<(), Box<dyn Error>> {
+    let (client, mut session) = open_session(data_dir, session_file).await?;
+    let response = sync_once(&client, &sync_settings(&session)).await?;
+    session.sync_token = Some(response.next_batch);
+    let room = address::room(&client, address, data_dir).await?;
+    let message = forward::message(&room, event_id).await?;
+    let room_name = room.display_name().await?.to_string();
+    for address in addresses {
+        let to = address::room(&client, address, data_dir).await?;
+        prepare_room(&to).await?;
+        forward::forward(&client, &message, &room_name, &to).await?;
+    }
+    close_session(&client, session_file, session).await
+}
+
+async fn reply_command(
+    data_dir: &Path,
+    session_file: &Path,
+    address: &Address,
+) -> Result<(), Box<dyn Error>> {
+    let mut raw = Vec::new();
+    tokio::io::stdin().read_to_end(&mut raw).await?;
+    let text = mime::decode(&raw);
+    if text.trim().is_empty() {
+        return Err("No reply on input".into());
+    }
+    let (client, mut session) = open_session(data_dir, session_file).await?;
+    let response = sync_once(&client, &sync_settings(&session)).await?;
+    session.sync_token = Some(response.next_batch);
+    let room = address::room(&client, address, data_dir).await?;
+    let event_id = inbox::last_from_others(&client, &room)
+        .await?
+        .ok_or(format!("No message from someone else in {address}"))?;
+    prepare_room(&room).await?;
+    let content = RoomMessageEventContent::text_plain(text.trim_end());
+    let content = message::reply(&room, content, &event_id).await?;
+    rate_limited(|| async { Ok::<_, Box<dyn Error>>(room.send(content.clone()).await?) }).await?;
+    close_session(&client, session_file, session).await
+}
+
+async fn redact_command(
+    data_dir: &Path,
+    session_file: &Path,
+    address: &Address,
+    event_id: &EventId,
+    reason: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let (client, session) = open_session(data_dir, session_file).await?;
+    let room = address::room(&client, address, data_dir).await?;
+    rate_limited(|| async { Ok::<_, Box<dyn Error>>(room.redact(event_id, reason, None).await?) })
+        .await?;
+    close_session(&client, session_file, session).await
+}
+
+async fn verify_command(
+    data_dir: &Path,
+    session_file: &Path,
+    device_id: Option<OwnedDeviceId>,
+) -> Result<(), Box<dyn Error>> {
+    let (client, session) = open_session(data_dir, session_file).await?;
+    verify::verify(&client, device_id.as_deref()).await?;
+    close_session(&client, session_file, session).await
+}
+
+// Authenticate with the account password when the homeserver asks for it (UIA).
+fn password_auth(client: &Client, info: &UiaaInfo) -> Result<AuthData, io::Error> {
+    let user_id = client.user_id().unwrap();
+    let mut password = uiaa::Password::new(
+        UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+        getpass(&format!("Password for {user_id}: "))?,
+    );
+    password.session = info.session.clone();
+    Ok(AuthData::Password(password))
+}
+
+// Each profile keeps its session and store in a subdirectory. --data-dir is used as is, e.g.
+// for an instance per service user.
+fn data_dir(profile: Option<&str>, given: Option<&Path>) -> Result<PathBuf, Box<dyn Error>> {
+    if let Some(profile) = profile {
+        if profile.is_empty() || profile.contains('/') || profile.starts_with('.') {
+            return Err(format!("Invalid profile name: {profile}").into());
+        }
+    }
+    if let Some(data_dir) = given {
+        return Ok(data_dir.to_path_buf());
+    }
+    let data_dir = match (env::var_os("MATRIXMAIL_DATA_DIR"), &config::get().data_dir) {
+        (Some(data_dir), _) => PathBuf::from(data_dir),
+        // Configured for the profile already.
+        (None, Some(data_dir)) => return Ok(data_dir.clone()),
+        (None, None) => env::var("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|x| PathBuf::from(x).join(".local/share")))
+            .unwrap()
+            .join("matrixmail"),
+    };
+    match profile {
+        Some(profile) => Ok(data_dir.join(profile)),
+        None => Ok(data_dir),
+    }
+}
+
+// Options win over the configuration, and so do the options they conflict with. The
+// configuration wins over ~/.mailrc.
+fn apply_config(args: &mut Args, mailrc: &Mailrc) -> Result<(), Box<dyn Error>> {
+    let settings = config::get();
+    let content_given =
+        args.location.is_some() || args.event_type.is_some() || args.sticker.is_some();
+    if args.msgtype.is_none() && !args.notice && !args.emote && !content_given {
+        args.msgtype = settings.msgtype.clone();
+    }
+    if !args.no_markdown && !content_given {
+        args.markdown |= settings.markdown.unwrap_or(false);
+    }
+    if args.sent_room.is_none() && args.event_type.is_none() {
+        args.sent_room = settings
+            .sent_room
+            .as_deref()
+            .map(str::parse::<Address>)
+            .transpose()?;
+    }
+    if args.record.is_none() && !args.record_by_recipient {
+        args.record = settings.record.clone().or(mailrc.record.clone());
+    }
+    Ok(())
+}
+
+fn config_dir() -> PathBuf {
+    env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|x| PathBuf::from(x).join(".config")))
+        .unwrap()
+        .join("matrixmail")
+}
+
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() {
     //#[cfg(debug_assertions)]
     //tracing_subscriber::fmt::init();
 
+    if let Err(e) = run().await {
+        eprintln!("Error: {e}");
+        process::exit(sysexits::status(&*e));
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
     unsafe { libc::umask(0o077) };
-    let data_dir = env::var("XDG_DATA_HOME")
-        .and_then(|x| Ok(PathBuf::from(x)))
-        .or_else(|_| env::var("HOME").and_then(|x| Ok(PathBuf::from(x).join(".local/share"))))
-        .unwrap()
-        .join("matrixmail");
-    let session_file = data_dir.join("login");
 
-    let arg0 = env::args().nth(0).unwrap();
+    let arg0 = env::args().next().unwrap();
     let name = Path::new(&arg0).file_name().unwrap().to_str().unwrap();
     if name != "mail" && name != "mailx" {
-        let client = login(&data_dir).await?;
-        let auth_session = client.matrix_auth().session().unwrap();
-        let session = Session {
-            homeserver: client.homeserver().to_string(),
-            user_id: auth_session.meta.user_id,
-            device_id: auth_session.meta.device_id,
-            access_token: auth_session.tokens.access_token,
-            refresh_token: auth_session.tokens.refresh_token,
-            sync_token: None,
+        let cli = Cli::parse();
+        start_deadline(cli.deadline);
+        config::load(&config_dir().join("config.toml"), cli.profile.as_deref()).await?;
+        set_network(&cli.network)?;
+        let data_dir = data_dir(cli.profile.as_deref(), cli.data_dir.as_deref())?;
+        let session_file = cli.session_file.unwrap_or(data_dir.join("login"));
+        if cli.queue {
+            return queue::flush(&data_dir).await;
+        }
+        return match cli.command {
+            Some(Command::Login(args)) => login_command(&data_dir, &session_file, args).await,
+            None => login_command(&data_dir, &session_file, LoginArgs::default()).await,
+            Some(Command::Logout) => logout(&data_dir, &session_file).await,
+            Some(Command::Whoami) => whoami(&data_dir, &session_file).await,
+            Some(Command::Register(args)) => register_command(&data_dir, &session_file, args).await,
+            Some(Command::Devices { command }) => {
+                devices_command(&data_dir, &session_file, command).await
+            }
+            Some(Command::Invites { command }) => {
+                invites_command(&data_dir, &session_file, command).await
+            }
+            Some(Command::Crypto { command }) => {
+                crypto_command(&data_dir, &session_file, command).await
+            }
+            Some(Command::Flush) => queue::flush(&data_dir).await,
+            Some(Command::Read) => read_command(&data_dir, &session_file, false).await,
+            Some(Command::Export {
+                room,
+                since,
+                format,
+                download_media,
+                file,
+            }) => {
+                export_command(
+                    &data_dir,
+                    &session_file,
+                    &room,
+                    since,
+                    format,
+                    &file,
+                    download_media.as_deref(),
+                )
+                .await
+            }
+            Some(Command::Maildir { dir, rules }) => {
+                maildir_command(&data_dir, &session_file, &dir, rules).await
+            }
+            Some(Command::Tail { addresses, format }) => {
+                tail_command(&data_dir, &session_file, &addresses, format).await
+            }
+            Some(Command::Rooms { format }) => {
+                rooms_command(&data_dir, &session_file, format).await
+            }
+            Some(Command::Leave { address, forget }) => {
+                leave_command(&data_dir, &session_file, &address, forget).await
+            }
+            Some(Command::RoomInfo { address }) => {
+                room_info_command(&data_dir, &session_file, &address).await
+            }
+            Some(Command::Search {
+                pattern,
+                rooms,
+                since,
+            }) => search_command(&data_dir, &session_file, &pattern, &rooms, since).await,
+            Some(Command::Reap) => reap_command(&data_dir, &session_file).await,
+            Some(Command::React {
+                address,
+                event_id,
+                key,
+            }) => react_command(&data_dir, &session_file, &address, event_id, key).await,
+            Some(Command::MarkRead { address, event_id }) => {
+                mark_read_command(&data_dir, &session_file, &address, event_id).await
+            }
+            Some(Command::Forward {
+                address,
+                event_id,
+                to,
+            }) => forward_command(&data_dir, &session_file, &address, &event_id, &to).await,
+            Some(Command::Reply { address }) => {
+                reply_command(&data_dir, &session_file, &address).await
+            }
+            Some(Command::Redact {
+                address,
+                event_id,
+                reason,
+            }) => {
+                redact_command(
+                    &data_dir,
+                    &session_file,
+                    &address,
+                    &event_id,
+                    reason.as_deref(),
+                )
+                .await
+            }
+            Some(Command::Verify { device_id }) => {
+                verify_command(&data_dir, &session_file, device_id).await
+            }
+            Some(Command::Completions { shell }) => completions::script(shell),
         };
-        save_session(&session_file, &session)
-            .await
-            .expect("Error saving session");
-        return Ok(());
     }
 
-    let args = Args::parse();
-    let mut body = String::new();
-    tokio::io::stdin().read_to_string(&mut body).await?;
-    let message = match args.subject {
-        Some(subject) => format!("{}\n\n{}", subject.trim(), body.trim()),
-        None => String::from(body.trim()),
+    let mut args = Args::parse();
+    VERBOSE.store(args.verbose, Ordering::Relaxed);
+    start_deadline(args.deadline);
+    config::load(&config_dir().join("config.toml"), args.profile.as_deref()).await?;
+    set_network(&args.network)?;
+    let mut mailrc = mailrc::load().await?;
+    for (name, addresses) in &config::get().aliases {
+        mailrc.set_alias(name, addresses.to_vec());
+    }
+    apply_config(&mut args, &mailrc)?;
+    let data_dir = data_dir(args.profile.as_deref(), args.data_dir.as_deref())?;
+    let session_file = args.session_file.clone().unwrap_or(data_dir.join("login"));
+    if args.complete_addresses {
+        let book = addressbook::load(&config_dir().join("addressbook.toml")).await?;
+        completions::addresses(&data_dir, &session_file, &mailrc, &book).await;
+        return Ok(());
+    }
+    // Without recipients, mailx reads mail instead.
+    if args.addresses.is_empty()
+        && args.cc.is_empty()
+        && args.bcc.is_empty()
+        && !args.read_recipients
+    {
+        return read_command(&data_dir, &session_file, args.check).await;
+    }
+    let book = addressbook::load(&config_dir().join("addressbook.toml")).await?;
+    let mut to = mailrc.expand(&args.addresses, &book)?;
+    let mut cc = mailrc.expand(&args.cc, &book)?;
+    let mut bcc = mailrc.expand(&args.bcc, &book)?;
+    // Like mailx, ask for the subject before the message is typed on the terminal.
+    let terminal = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    if mailrc.asksub && args.subject.is_none() && !args.read_recipients && terminal {
+        args.subject = Some(prompt("Subject: ")?);
+    }
+    let mut raw = Vec::new();
+    tokio::io::stdin().read_to_end(&mut raw).await?;
+    let mut body = mime::decode(&raw);
+    // Like mailx -E, e.g. for cron jobs that only sometimes have output.
+    if args.discard_empty && body.trim().is_empty() && args.attachments.is_empty() {
+        return Ok(());
+    }
+    let due = match (args.delay, args.send_at) {
+        (Some(delay), _) => Some(queue::now() + delay),
+        (None, due) => due,
     };
-
-    let mut session = load_session(&session_file)
-        .await
-        .expect("Error loading session");
-    let client = Client::builder()
-        .homeserver_url(Url::parse(&session.homeserver)?)
-        .sqlite_store(&data_dir, None)
-        .build()
-        .await?;
-    let auth_session = MatrixSession {
-        meta: SessionMeta {
-            user_id: session.user_id.clone(),
-            device_id: session.device_id.clone(),
-        },
-        tokens: MatrixSessionTokens {
-            access_token: session.access_token.clone(),
-            refresh_token: session.refresh_token.clone(),
-        },
+    if let Some(due) = due {
+        return queue::park(&data_dir, due, body, &args.attachments).await;
+    }
+    let input = body.clone();
+    let mut subject = args.subject.clone();
+    if args.read_recipients {
+        let (headers, rest) = headers::parse(&body)?;
+        to.extend(mailrc.expand(&headers.to, &book)?);
+        cc.extend(mailrc.expand(&headers.cc, &book)?);
+        bcc.extend(mailrc.expand(&headers.bcc, &book)?);
+        subject = subject.or(headers.subject);
+        body = rest;
+    }
+    let mut html = None;
+    let mut mime_attachments = Vec::new();
+    let mut inline_images = Vec::new();
+    if mime::is_mime(&raw) {
+        let mail = mime::parse(&raw)?;
+        subject = subject.or(mail.subject);
+        body = mail.text;
+        html = mail.html;
+        mime_attachments = mail.attachments;
+        inline_images = mail.inline;
+    }
+    // Everyone gets the message once, the most visible way.
+    cc.retain(|address| !address::contains(&to, address));
+    bcc.retain(|address| !address::contains(&to, address) && !address::contains(&cc, address));
+    if to.is_empty() && cc.is_empty() && bcc.is_empty() {
+        return Err("No recipients".into());
+    }
+    // Headers or MIME parts alone are empty too.
+    if args.discard_empty
+        && body.trim().is_empty()
+        && mime_attachments.is_empty()
+        && args.attachments.is_empty()
+    {
+        return Ok(());
+    }
+    // Only text messages get the signature, not JSON events, locations or stickers, nor empty
+    // messages that only carry attachments or a poll.
+    let signed = args.event_type.is_none() && args.location.is_none() && args.sticker.is_none();
+    if signed && !args.no_sig && !body.trim().is_empty() {
+        match fs::read_to_string(config_dir().join("signature")).await {
+            // Separated from the body by "-- ", like classic mail clients do.
+            Ok(signature) => {
+                let signature = signature.trim_end();
+                body = format!("{}\n\n-- \n{signature}", body.trim_end());
+                html = html.map(|html| {
+                    let signature = message::escape_html(signature).replace('\n', "<br>");
+                    format!("{html}\n<p>-- <br>{signature}</p>")
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let template = match (&args.template, &subject) {
+        (Some(template), subject) => template::render(
+            &fs::read_to_string(template).await?,
+            subject.as_deref().unwrap_or("").trim(),
+            body.trim(),
+        )?,
+        (None, Some(subject)) => {
+            template::Message::text(&format!("{}\n\n{}", subject.trim(), body.trim()))
+        }
+        (None, None) => template::Message::text(body.trim()),
     };
-    client
-        .restore_session(auth_session)
-        .await
-        .expect("Error restoring session");
+    let message = template.to_string();
+    // A template lays out the text only.
+    let html = match (&args.template, html, &subject) {
+        (None, Some(html), Some(subject)) => Some(format!(
+            "<p>{}</p>\n{html}",
+            message::escape_html(subject.trim())
+        )),
+        (None, html, _) => html,
+        (Some(_), _, _) => None,
+    };
+    // With copies, the recipients see who else got the message, except for the blind copies.
+    let list = |addresses: &[Address]| {
+        let addresses: Vec<String> = addresses.iter().map(Address::to_string).collect();
+        addresses.join(", ")
+    };
+    let visible_template = match cc.is_empty() {
+        true => template.clone(),
+        false => template.prefixed(format!("To: {}\nCc: {}\n\n", list(&to), list(&cc))),
+    };
+    let visible_html = match cc.is_empty() {
+        true => html.clone(),
+        false => html.as_ref().map(|html| {
+            format!(
+                "<p>To: {}<br>Cc: {}</p>\n{html}",
+                message::escape_html(&list(&to)),
+                message::escape_html(&list(&cc))
+            )
+        }),
+    };
+    let mut attachments = Vec::new();
+    for path in &args.attachments {
+        attachments.push(attachment::load(path).await?);
+    }
+    attachments.extend(mime_attachments);
+
+    trace(format_args!(
+        "Restoring the session from {}",
+        session_file.display()
+    ));
+    let (client, mut session) = open_session(&data_dir, &session_file).await?;
+    trace(format_args!(
+        "Logged in as {} on {}, device {}",
+        session.user_id, session.homeserver, session.device_id
+    ));
 
     // Speed up initial sync for accounts in many rooms.
     let filter = FilterDefinition::with_lazy_loading();
     let mut sync_settings = SyncSettings::default().filter(filter.into());
-    if let Some(sync_token) = session.sync_token {
+    if let Some(sync_token) = session.sync_token.clone() {
         sync_settings = sync_settings.token(sync_token);
     }
     // Initial sync.
-    let response = client.sync_once(sync_settings.clone()).await?;
+    trace("Syncing");
+    let start = Instant::now();
+    let response = match sync_once(&client, &sync_settings).await {
+        // Keep the message for later, e.g. when cron runs on a laptop that is offline.
+        Err(e) if unreachable(&*e) && !queue::flushing() => {
+            eprintln!("Homeserver unreachable, message queued, send it with `matrixmail flush`.");
+            return queue::park(&data_dir, queue::now(), input, &args.attachments).await;
+        }
+        Err(e) => match e
+            .downcast_ref::<matrix_sdk::Error>()
+            .and_then(unknown_token)
+        {
+            Some(soft_logout) => {
+                session_expired(&client, &session, soft_logout).await?;
+                sync_once(&client, &sync_settings).await?
+            }
+            None => return Err(e),
+        },
+        Ok(response) => response,
+    };
     sync_settings = sync_settings.token(response.next_batch.clone());
     session.sync_token = Some(response.next_batch.clone());
+    trace(format_args!("Synced in {:.1?}", start.elapsed()));
 
-    for address in &args.addresses {
-        // Send message.
-        send_message(&client, &address, &message)
-            .await
-            .expect(&format!("Error sending message to {}", address));
-        // Sync again.
-        let response = client.sync_once(sync_settings.clone()).await?;
-        sync_settings = sync_settings.token(response.next_batch.clone());
-        session.sync_token = Some(response.next_batch.clone());
+    if args.confirm {
+        let addresses: Vec<&Address> = to.iter().chain(&cc).chain(&bcc).collect();
+        if !confirm::confirm(&client, &addresses, &message, attachments.len()).await? {
+            update_session_tokens(&client, &mut session);
+            save_session(&session_file, &session).await?;
+            eprintln!("Not sent.");
+            process::exit(1);
+        }
     }
 
-    let auth_session = client.matrix_auth().session().unwrap();
-    session.access_token = auth_session.tokens.access_token.clone();
-    session.refresh_token = auth_session.tokens.refresh_token.clone();
+    // Uploaded once, when sending to the first unencrypted room.
+    let inline = attachment::Inline::new(inline_images);
+    let sticker = args
+        .sticker
+        .clone()
+        .map(|sticker| sticker::Upload::new(sticker, message.clone()));
+
+    let visible_mail = Mail {
+        text: &visible_template,
+        html: visible_html.as_deref(),
+        entry: None,
+        sticker: sticker.as_ref(),
+        attachments: &attachments,
+        inline: &inline,
+    };
+    let blind_mail = Mail {
+        text: &template,
+        html: html.as_deref(),
+        ..visible_mail
+    };
+    let mut recipients: Vec<(&Address, Mail)> = to
+        .iter()
+        .chain(&cc)
+        .map(|address| (address, visible_mail))
+        .chain(bcc.iter().map(|address| (address, blind_mail)))
+        .map(|(address, mail)| {
+            let entry = addressbook::entry(&book, address);
+            (address, Mail { entry, ..mail })
+        })
+        .collect();
+    // The archive copy says who got the message, including the blind copies. A sent room that
+    // is also a recipient has the message already.
+    let mut archive_text = String::new();
+    for (name, addresses) in [("To", &to), ("Cc", &cc), ("Bcc", &bcc)] {
+        if !addresses.is_empty() {
+            archive_text.push_str(&format!("{name}: {}\n", list(addresses)));
+        }
+    }
+    archive_text.push_str(&format!("\n{message}"));
+    let archive_text = template::Message::text(&archive_text);
+    let recipient_count = recipients.len();
+    if let Some(sent_room) = &args.sent_room {
+        let name = sent_room.to_string();
+        if !recipients
+            .iter()
+            .any(|(address, _)| address.to_string() == name)
+        {
+            let archive_mail = Mail {
+                text: &archive_text,
+                html: None,
+                ..blind_mail
+            };
+            recipients.push((sent_room, archive_mail));
+        }
+    }
+    // Recipients in the address book may be sent to differently.
+    let book_args: HashMap<String, Args> = recipients
+        .iter()
+        .filter_map(|(address, _)| {
+            let book_args = addressbook::args(&book, address, &args)?;
+            Some((address.to_string(), book_args))
+        })
+        .collect();
+    let args_of = |address: &Address| book_args.get(&address.to_string()).unwrap_or(&args);
+    let in_flight = inflight::begin(&data_dir, &input).await?;
+    // Send message to all rooms at once.
+    let sends = recipients.iter().map(|&(address, mail)| {
+        let args = args_of(address);
+        traced(
+            address,
+            send_message(&client, address, mail, args, &data_dir, &in_flight),
+        )
+    });
+    let results: Vec<_> = stream::iter(sends)
+        .buffered(args.max_concurrency.into())
+        .collect()
+        .await;
+    let mut sent = Vec::new();
+    let mut failed = Vec::new();
+    let mut logged_in_again = false;
+    for (&(address, mail), result) in recipients.iter().zip(results) {
+        let result = match result {
+            Err(e) => match e
+                .downcast_ref::<matrix_sdk::Error>()
+                .and_then(unknown_token)
+            {
+                // The sends that failed together only need one new login.
+                Some(soft_logout) => {
+                    if !logged_in_again {
+                        trace("The session expired, logging in again");
+                        session_expired(&client, &session, soft_logout).await?;
+                        logged_in_again = true;
+                    }
+                    let args = args_of(address);
+                    let send = send_message(&client, address, mail, args, &data_dir, &in_flight);
+                    traced(address, send).await
+                }
+                None => Err(e),
+            },
+            result => result,
+        };
+        match result {
+            Ok(result) => sent.push(result),
+            Err(e) => {
+                let e = sysexits::context(e, format_args!("Error sending message to {address}"));
+                eprintln!("{e}");
+                failed.push(e);
+            }
+        }
+    }
+    // Failures worth trying again decide the exit status. Then the message stays in flight, so
+    // that running matrixmail again only delivers the failed messages.
+    if !failed.is_empty() {
+        update_session_tokens(&client, &mut session);
+        save_session(&session_file, &session).await?;
+        let status = failed
+            .iter()
+            .map(|e| sysexits::status(&**e))
+            .find(|&status| status == sysexits::EX_TEMPFAIL)
+            .unwrap_or(sysexits::status(&*failed[0]));
+        if status != sysexits::EX_TEMPFAIL {
+            in_flight.end().await?;
+        }
+        return Err(sysexits::fail(
+            status,
+            format_args!("{} of {} messages not sent", failed.len(), recipients.len()),
+        ));
+    }
+    in_flight.end().await?;
+    let record_file = match (&args.record, args.record_by_recipient) {
+        (Some(file), _) => Some(file.clone()),
+        // There always is a recipient.
+        (None, true) => Some(record::file_name(
+            to.iter().chain(&cc).chain(&bcc).next().unwrap(),
+        )),
+        (None, false) => None,
+    };
+    if let Some(file) = record_file {
+        let record = record::Record {
+            to: &to,
+            cc: &cc,
+            bcc: &bcc,
+            subject: subject.as_deref().map(str::trim),
+            body: match &args.template {
+                Some(_) => &message,
+                None => body.trim(),
+            },
+        };
+        record::append(&file, &record).await?;
+    }
+    let due = args.expire.map(|ttl| queue::now() + ttl);
+    if let Some(due) = due {
+        expire::record(&data_dir, due, &sent).await?;
+    }
+    // Sync again.
+    trace("Syncing again");
+    let response = sync_once(&client, &sync_settings).await?;
+    sync_settings = sync_settings.token(response.next_batch.clone());
+    session.sync_token = Some(response.next_batch.clone());
+
+    let read = match args.wait_read {
+        Some(timeout) => {
+            let timeout = Duration::from_secs(timeout);
+            trace("Waiting for the messages to be read");
+            // There is always a message or an attachment, the last one is read last.
+            // Not waiting for the archive copy.
+            let last: Vec<_> = sent[..recipient_count]
+                .iter()
+                .map(|(room_id, event_ids)| (room_id.clone(), event_ids.last().unwrap().clone()))
+                .collect();
+            receipts::wait_read(
+                &client,
+                &last,
+                sync_settings,
+                &mut session.sync_token,
+                timeout,
+            )
+            .await?
+        }
+        None => true,
+    };
+
+    // Short-lived messages are redacted right away rather than by the next reap.
+    if let Some(due) = due.filter(|_| args.expire.is_some_and(|ttl| ttl <= EXPIRE_WAIT)) {
+        let wait = due - queue::now();
+        if wait > 0 {
+            tokio::time::sleep(Duration::from_secs(wait as u64)).await;
+        }
+        expire::reap(&client, &data_dir).await?;
+    }
+
+    update_session_tokens(&client, &mut session);
     save_session(&session_file, &session)
         .await
-        .expect("Error saving session");
+        .map_err(|e| sysexits::context(e, "Error saving session"))?;
 
+    if !read {
+        eprintln!("Message not read in time.");
+        process::exit(EXIT_NOT_READ);
+    }
     Ok(())
 }
@@ -0,0 +1,47 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Media for events, uploaded to the media repository of the homeserver. The homeserver and
+// anyone with the URI can read it, so for encrypted rooms the file is encrypted with AES-CTR
+// before uploading, and the event refers to it by the key and hashes to decrypt it with.
+
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::Client;
+use mime::Mime;
+use std::error::Error;
+
+pub async fn upload(
+    client: &Client,
+    content_type: &Mime,
+    data: &[u8],
+    encrypted: bool,
+) -> Result<MediaSource, Box<dyn Error>> {
+    Ok(match encrypted {
+        true => {
+            let mut reader = data;
+            let file = client
+                .prepare_encrypted_file(content_type, &mut reader)
+                .await?;
+            MediaSource::Encrypted(Box::new(file))
+        }
+        false => {
+            let response = client.media().upload(content_type, data.to_vec()).await?;
+            MediaSource::Plain(response.content_uri)
+        }
+    })
+}
@@ -0,0 +1,372 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// The message event, built from the message text and the options.
+
+use crate::state;
+use crate::Args;
+use matrix_sdk::ruma::events::relation::Replacement;
+use matrix_sdk::ruma::events::relation::Thread;
+use matrix_sdk::ruma::events::room::message::AddMentions;
+use matrix_sdk::ruma::events::room::message::EmoteMessageEventContent;
+use matrix_sdk::ruma::events::room::message::FormattedBody;
+use matrix_sdk::ruma::events::room::message::ForwardThread;
+use matrix_sdk::ruma::events::room::message::LocationMessageEventContent;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::Relation;
+use matrix_sdk::ruma::events::room::message::RoomMessageEvent;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContentWithoutRelation;
+use matrix_sdk::ruma::events::Mentions;
+use matrix_sdk::ruma::serde::JsonObject;
+use matrix_sdk::ruma::EventId;
+use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::Room;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+
+// A position given as "lat,lon[;description]".
+#[derive(Clone, Debug)]
+pub struct Location {
+    latitude: f64,
+    longitude: f64,
+    description: Option<String>,
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (position, description) = match s.split_once(';') {
+            Some((position, description)) => (position, Some(String::from(description))),
+            None => (s, None),
+        };
+        let (latitude, longitude) = position
+            .split_once(',')
+            .ok_or("expected latitude,longitude")?;
+        let latitude: f64 = latitude.trim().parse().map_err(|_| "invalid latitude")?;
+        let longitude: f64 = longitude.trim().parse().map_err(|_| "invalid longitude")?;
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(String::from("position out of range"));
+        }
+        Ok(Location {
+            latitude,
+            longitude,
+            description,
+        })
+    }
+}
+
+// The message, if any, describes the location unless the option does.
+fn location_content(message: &str, location: &Location) -> RoomMessageEventContent {
+    let position = format!("{},{}", location.latitude, location.longitude);
+    let body = match (&location.description, message.is_empty()) {
+        (Some(description), _) => description.clone(),
+        (None, false) => String::from(message),
+        (None, true) => format!("Location {position}"),
+    };
+    let location = LocationMessageEventContent::new(body, format!("geo:{position}"));
+    RoomMessageEventContent::new(MessageType::Location(location))
+}
+
+pub fn content(
+    message: &str,
+    html: Option<&str>,
+    args: &Args,
+) -> Result<RoomMessageEventContent, Box<dyn Error>> {
+    if let Some(location) = &args.location {
+        return Ok(location_content(message, location));
+    }
+    let msgtype = match (&args.msgtype, args.notice, args.emote) {
+        (Some(msgtype), _, _) => msgtype.as_str(),
+        (None, true, _) => "m.notice",
+        (None, _, true) => "m.emote",
+        (None, false, false) => "m.text",
+    };
+    // An HTML version from a MIME message takes precedence over Markdown.
+    Ok(match (msgtype, html, args.markdown) {
+        ("m.text", Some(html), _) => RoomMessageEventContent::text_html(message, html),
+        ("m.text", None, false) => RoomMessageEventContent::text_plain(message),
+        ("m.text", None, true) => RoomMessageEventContent::text_markdown(message),
+        ("m.notice", Some(html), _) => RoomMessageEventContent::notice_html(message, html),
+        ("m.notice", None, false) => RoomMessageEventContent::notice_plain(message),
+        ("m.notice", None, true) => RoomMessageEventContent::notice_markdown(message),
+        ("m.emote", Some(html), _) => RoomMessageEventContent::new(MessageType::Emote(
+            EmoteMessageEventContent::html(message, html),
+        )),
+        ("m.emote", None, false) => RoomMessageEventContent::emote_plain(message),
+        ("m.emote", None, true) => RoomMessageEventContent::emote_markdown(message),
+        // Custom message types only have a body.
+        (msgtype, _, _) => RoomMessageEventContent::new(MessageType::new(
+            msgtype,
+            String::from(message),
+            JsonObject::new(),
+        )?),
+    })
+}
+
+// The content of a part of a message split at the size. The formatted body counts as well, and
+// is left out when the part only fits as plain text.
+pub fn sized_content(
+    part: &str,
+    html: Option<&str>,
+    args: &Args,
+) -> Result<RoomMessageEventContent, Box<dyn Error>> {
+    let formatted = content(part, html, args)?;
+    if serde_json::to_vec(&formatted)?.len() <= args.split_size {
+        return Ok(formatted);
+    }
+    let plain = Args {
+        markdown: false,
+        ..args.clone()
+    };
+    content(part, None, &plain)
+}
+
+// Cut a line that doesn't fit into a part on its own.
+fn cut(line: &str, size: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = line;
+    while rest.len() > size {
+        let mut end = size;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        pieces.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    pieces.push(rest);
+    pieces
+}
+
+// Split a message that is too large for one event into numbered parts at line breaks.
+// A code block that spans parts is closed at the end of a part and opened again in the next.
+pub fn split(message: &str, size: usize) -> Vec<String> {
+    if message.len() <= size {
+        return vec![String::from(message)];
+    }
+    // Leave room for the part number and the fences.
+    let size = size.saturating_sub(64).max(64);
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut fence: Option<String> = None;
+    for line in message.lines().flat_map(|line| cut(line, size)) {
+        if !part.is_empty() && part.len() + 1 + line.len() > size {
+            if fence.is_some() {
+                part.push_str("\n```");
+            }
+            parts.push(std::mem::take(&mut part));
+            if let Some(fence) = &fence {
+                part.push_str(fence);
+            }
+        }
+        if !part.is_empty() {
+            part.push('\n');
+        }
+        part.push_str(line);
+        if line.trim_start().starts_with("```") {
+            fence = match fence {
+                Some(_) => None,
+                None => Some(String::from(line.trim_start())),
+            };
+        }
+    }
+    parts.push(part);
+    let count = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| format!("({}/{count})\n{part}", i + 1))
+        .collect()
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Put pills of the users in front of the message.
+fn add_pills(body: &mut String, formatted: &mut Option<FormattedBody>, user_ids: &[OwnedUserId]) {
+    let html = match formatted.take() {
+        Some(formatted) => formatted.body,
+        None => escape_html(body).replace('\n', "<br>"),
+    };
+    let names: Vec<String> = user_ids.iter().map(|user_id| user_id.to_string()).collect();
+    let pills: Vec<String> = user_ids
+        .iter()
+        .map(|user_id| format!("<a href=\"{}\">{user_id}</a>", user_id.matrix_to_uri()))
+        .collect();
+    *body = format!("{}: {body}", names.join(" "));
+    *formatted = Some(FormattedBody::html(format!("{}: {html}", pills.join(" "))));
+}
+
+// Mention the users, so that their clients notify them.
+pub fn mention(
+    mut content: RoomMessageEventContent,
+    user_ids: &[OwnedUserId],
+) -> RoomMessageEventContent {
+    match &mut content.msgtype {
+        MessageType::Text(text) => add_pills(&mut text.body, &mut text.formatted, user_ids),
+        MessageType::Notice(notice) => add_pills(&mut notice.body, &mut notice.formatted, user_ids),
+        MessageType::Emote(emote) => add_pills(&mut emote.body, &mut emote.formatted, user_ids),
+        _ => {}
+    }
+    content.mentions = Some(Mentions::with_user_ids(user_ids.iter().cloned()));
+    content
+}
+
+// Make the content a rich reply, quoting the original message as fallback.
+pub async fn reply(
+    room: &Room,
+    content: RoomMessageEventContent,
+    event_id: &EventId,
+) -> Result<RoomMessageEventContent, Box<dyn Error>> {
+    let event: RoomMessageEvent = room.event(event_id).await?.event.deserialize_as()?;
+    let original = event
+        .as_original()
+        .ok_or(format!("Can't reply to {event_id}, it was redacted"))?;
+    Ok(content.make_reply_to(original, ForwardThread::Yes, AddMentions::Yes))
+}
+
+// Make the content part of the thread with the given root event.
+pub fn in_thread(mut content: RoomMessageEventContent, root: &EventId) -> RoomMessageEventContent {
+    let thread = Thread::plain(root.to_owned(), root.to_owned());
+    content.relates_to = Some(Relation::Thread(thread));
+    content
+}
+
+// Make the content replace an earlier message, which clients show edited in place.
+pub fn replacement(
+    mut content: RoomMessageEventContent,
+    event_id: &EventId,
+) -> RoomMessageEventContent {
+    let new_content = RoomMessageEventContentWithoutRelation::from(content.clone());
+    // Clients without edits show the fallback as a new message.
+    let (body, formatted) = match &mut content.msgtype {
+        MessageType::Text(text) => (&mut text.body, &mut text.formatted),
+        MessageType::Notice(notice) => (&mut notice.body, &mut notice.formatted),
+        MessageType::Emote(emote) => (&mut emote.body, &mut emote.formatted),
+        _ => return content,
+    };
+    *body = format!("* {body}");
+    if let Some(formatted) = formatted {
+        formatted.body = format!("* {}", formatted.body);
+    }
+    let replacement = Replacement::new(event_id.to_owned(), new_content);
+    content.relates_to = Some(Relation::Replacement(replacement));
+    content
+}
+
+// The event type of polls (MSC3381), still unstable but shown by Element and others.
+pub const POLL_START: &str = "org.matrix.msc3381.poll.start";
+
+// A poll where one option can be chosen and everyone sees the results while voting, with the
+// question and options as text for clients without polls.
+pub fn poll(question: &str, options: &[String]) -> Result<serde_json::Value, Box<dyn Error>> {
+    if options.len() < 2 {
+        return Err("A poll needs at least two options".into());
+    }
+    let answers: Vec<serde_json::Value> = options
+        .iter()
+        .enumerate()
+        .map(|(i, option)| {
+            serde_json::json!({
+                "id": (i + 1).to_string(),
+                "org.matrix.msc1767.text": option,
+            })
+        })
+        .collect();
+    let mut text = String::from(question);
+    for (i, option) in options.iter().enumerate() {
+        text.push_str(&format!("\n{}. {option}", i + 1));
+    }
+    Ok(serde_json::json!({
+        POLL_START: {
+            "question": { "org.matrix.msc1767.text": question },
+            "kind": "org.matrix.msc3381.poll.disclosed",
+            "max_selections": 1,
+            "answers": answers,
+        },
+        "org.matrix.msc1767.text": text,
+    }))
+}
+
+// Ask clients not to show previews of the links in the message (MSC4095).
+pub fn without_previews(
+    content: &RoomMessageEventContent,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    let mut content = serde_json::to_value(content)?;
+    content["com.beeper.linkpreviews"] = serde_json::json!([]);
+    Ok(content)
+}
+
+// An event per room, like the last message sent or the root of the thread started with
+// --thread-latest, kept in a file in the data directory.
+type RoomEvents = HashMap<OwnedRoomId, OwnedEventId>;
+
+pub async fn room_event(
+    file: &Path,
+    room_id: &RoomId,
+) -> Result<Option<OwnedEventId>, Box<dyn Error>> {
+    let events: RoomEvents = state::read(file).await?;
+    Ok(events.get(room_id).cloned())
+}
+
+pub async fn save_room_event(
+    file: &Path,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> Result<(), Box<dyn Error>> {
+    state::update(file, |events: &mut RoomEvents| {
+        events.insert(room_id.to_owned(), event_id.to_owned())
+    })
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_reopens_code_block() {
+        let lines: Vec<String> = (0..10)
+            .map(|i| format!("line {i:02} of the code"))
+            .collect();
+        let message = format!("```sh\n{}\n```", lines.join("\n"));
+        let parts = split(&message, 128);
+        assert!(parts.len() > 1);
+        assert!(parts[1].starts_with(&format!("(2/{})\n```sh\n", parts.len())));
+        for part in &parts {
+            assert_eq!(part.matches("```").count() % 2, 0, "{part}");
+        }
+    }
+
+    #[test]
+    fn cut_at_char_boundary() {
+        assert_eq!(cut("aéé", 2), ["a", "é", "é"]);
+        assert_eq!(cut("abc", 3), ["abc"]);
+    }
+}
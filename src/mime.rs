@@ -0,0 +1,153 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Complete MIME messages on stdin, as a mail server's pipe transport hands them over: the
+// text/plain part is the message, the text/html part its formatted version, and all other
+// parts are sent as attachments. Quoted-printable and base64 are decoded, and other charsets
+// converted to UTF-8. Images that the HTML shows inline with cid: URLs are kept separately, to
+// upload them and point the URLs to the uploads.
+
+use crate::attachment::Attachment;
+use mailparse::MailHeaderMap;
+use mailparse::ParsedMail;
+use std::error::Error;
+
+#[derive(Default)]
+pub struct Mail {
+    pub subject: Option<String>,
+    pub text: String,
+    pub html: Option<String>,
+    pub attachments: Vec<Attachment>,
+    // By Content-ID.
+    pub inline: Vec<(String, Attachment)>,
+}
+
+// Only messages that say so are parsed, anything else stays plain text. Some mailers leave out
+// MIME-Version but still encode the message.
+pub fn is_mime(message: &[u8]) -> bool {
+    match mailparse::parse_headers(message) {
+        Ok((headers, _)) => ["MIME-Version", "Content-Type", "Content-Transfer-Encoding"]
+            .iter()
+            .any(|name| headers.get_first_value(name).is_some()),
+        Err(_) => false,
+    }
+}
+
+// Input that isn't UTF-8 is most likely Latin-1, or Windows-1252, which only differs from it
+// in control characters.
+pub fn decode(input: &[u8]) -> String {
+    match std::str::from_utf8(input) {
+        Ok(text) => String::from(text),
+        Err(_) => encoding_rs::WINDOWS_1252.decode(input).0.into_owned(),
+    }
+}
+
+// Encoded words like =?ISO-8859-1?Q?...?= in a header, e.g. the subject.
+pub fn decode_header(value: &str) -> String {
+    match mailparse::parse_header(format!("X: {value}").as_bytes()) {
+        Ok((header, _)) => header.get_value(),
+        Err(_) => String::from(value),
+    }
+}
+
+// For mails without a text/plain part.
+fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+// The first text/plain and text/html parts that aren't attached files make the message, in
+// multipart/alternative and multipart/mixed alike.
+fn walk(
+    part: &ParsedMail,
+    text: &mut Option<String>,
+    mail: &mut Mail,
+) -> Result<(), Box<dyn Error>> {
+    if part.ctype.mimetype.starts_with("multipart/") {
+        for subpart in &part.subparts {
+            walk(subpart, text, mail)?;
+        }
+        return Ok(());
+    }
+    let disposition = part.get_content_disposition();
+    let filename = disposition
+        .params
+        .get("filename")
+        .or(part.ctype.params.get("name"));
+    let content_id = part.headers.get_first_value("Content-ID");
+    match (part.ctype.mimetype.as_str(), filename) {
+        ("text/plain", None) if text.is_none() => *text = Some(part.get_body()?),
+        ("text/html", None) if mail.html.is_none() => mail.html = Some(part.get_body()?),
+        (content_type, filename) => {
+            let attachment = Attachment::new(
+                filename.cloned().unwrap_or(String::from("attachment")),
+                content_type.parse()?,
+                part.get_body_raw()?,
+            );
+            match content_id {
+                Some(content_id) if content_type.starts_with("image/") => {
+                    let content_id = content_id
+                        .trim()
+                        .trim_start_matches('<')
+                        .trim_end_matches('>');
+                    mail.inline.push((String::from(content_id), attachment));
+                }
+                _ => mail.attachments.push(attachment),
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn parse(message: &[u8]) -> Result<Mail, Box<dyn Error>> {
+    let parsed = mailparse::parse_mail(message)?;
+    let mut mail = Mail {
+        subject: parsed.headers.get_first_value("Subject"),
+        ..Mail::default()
+    };
+    let mut text = None;
+    walk(&parsed, &mut text, &mut mail)?;
+    // Images with a Content-ID that the HTML doesn't show are ordinary attachments.
+    let html = mail.html.as_deref().unwrap_or("");
+    let (inline, attachments): (Vec<_>, Vec<_>) = mail
+        .inline
+        .into_iter()
+        .partition(|(content_id, _)| html.contains(&format!("cid:{content_id}")));
+    mail.inline = inline;
+    mail.attachments
+        .extend(attachments.into_iter().map(|(_, attachment)| attachment));
+    mail.text = match (text, &mail.html) {
+        (Some(text), _) => text,
+        (None, Some(html)) => html_to_text(html),
+        (None, None) => String::new(),
+    };
+    Ok(mail)
+}
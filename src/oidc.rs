@@ -0,0 +1,337 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Native OIDC authentication (MSC3861).
+// matrix-sdk only implements the authorization code grant, so the browser redirects to a
+// listener on localhost, like the SSO login does. Logging in from another device uses the
+// device authorization grant (RFC 8628) instead, which QR code login (MSC4108) builds on.
+
+use crate::http_client;
+use matrix_sdk::oidc::types::client_credentials::ClientCredentials;
+use matrix_sdk::oidc::types::iana::oauth::OAuthClientAuthenticationMethod;
+use matrix_sdk::oidc::types::oidc::ApplicationType;
+use matrix_sdk::oidc::types::registration::ClientMetadata;
+use matrix_sdk::oidc::types::registration::Localized;
+use matrix_sdk::oidc::types::registration::VerifiedClientMetadata;
+use matrix_sdk::oidc::types::requests::GrantType;
+use matrix_sdk::oidc::AuthorizationCode;
+use matrix_sdk::oidc::OidcSession;
+use matrix_sdk::oidc::OidcSessionTokens;
+use matrix_sdk::oidc::UserSession;
+use matrix_sdk::ruma::api::client::discovery::discover_homeserver::AuthenticationServerInfo;
+use matrix_sdk::ruma::OwnedDeviceId;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::Client;
+use matrix_sdk::SessionMeta;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use serde::de;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use std::error::Error;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use url::Url;
+
+// The dynamically registered OIDC client, needed to refresh the tokens.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OidcRegistration {
+    issuer: String,
+    client_id: String,
+    #[serde(deserialize_with = "verified")]
+    client_metadata: VerifiedClientMetadata,
+}
+
+// Only the metadata as registered is saved, so it is verified again when loading it.
+fn verified<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<VerifiedClientMetadata, D::Error> {
+    ClientMetadata::deserialize(deserializer)?
+        .validate()
+        .map_err(de::Error::custom)
+}
+
+impl From<&OidcSession> for OidcRegistration {
+    fn from(session: &OidcSession) -> Self {
+        OidcRegistration {
+            issuer: session.user.issuer_info.issuer.clone(),
+            client_id: session.credentials.client_id().to_owned(),
+            client_metadata: session.metadata.clone(),
+        }
+    }
+}
+
+// The homeserver's OIDC provider, if its .well-known delegates authentication to one.
+pub fn issuer(client: &Client) -> Option<String> {
+    client
+        .oidc()
+        .authentication_server_info()
+        .map(|info| info.issuer.clone())
+}
+
+fn client_metadata(
+    redirect_uris: Option<Vec<Url>>,
+    grant_type: GrantType,
+) -> Result<VerifiedClientMetadata, Box<dyn Error>> {
+    let client_uri = Url::parse("https://github.com/apirek/matrixmail")?;
+    let metadata = ClientMetadata {
+        application_type: Some(ApplicationType::Native),
+        redirect_uris,
+        grant_types: Some(vec![grant_type, GrantType::RefreshToken]),
+        token_endpoint_auth_method: Some(OAuthClientAuthenticationMethod::None),
+        client_name: Some(Localized::new(String::from("matrixmail"), [])),
+        client_uri: Some(Localized::new(client_uri, [])),
+        ..Default::default()
+    };
+    Ok(metadata.validate()?)
+}
+
+// Wait for the browser to be redirected to the listener and return the authorization code.
+async fn wait_for_redirect(listener: TcpListener) -> Result<AuthorizationCode, Box<dyn Error>> {
+    let (mut stream, _) = listener.accept().await?;
+    let mut buffer = vec![0; 8192];
+    let n = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..n]);
+    // GET /?code=...&state=... HTTP/1.1
+    let path = request
+        .lines()
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .ok_or("Invalid redirect request")?;
+    let url = Url::parse("http://localhost")?.join(path)?;
+    let query = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let body = match (query("code"), query("state")) {
+        (Some(code), Some(state)) => Ok(AuthorizationCode { code, state }),
+        _ => Err(format!(
+            "Authorization failed: {}",
+            query("error").unwrap_or(String::from("no code in redirect"))
+        )),
+    };
+    let message = match body {
+        Ok(_) => "Login complete, you can close this window.",
+        Err(_) => "Login failed.",
+    };
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{message}",
+        message.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(body?)
+}
+
+pub async fn login(client: &Client, issuer: String, device_id: &str) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let redirect_uri = Url::parse(&format!(
+        "http://127.0.0.1:{}/",
+        listener.local_addr()?.port()
+    ))?;
+
+    let oidc = client.oidc();
+    let client_metadata = client_metadata(
+        Some(vec![redirect_uri.clone()]),
+        GrantType::AuthorizationCode,
+    )?;
+    let registration = oidc
+        .register_client(&issuer, client_metadata.clone(), None)
+        .await?;
+    oidc.restore_registered_client(
+        AuthenticationServerInfo::new(issuer, None),
+        client_metadata,
+        ClientCredentials::None {
+            client_id: registration.client_id,
+        },
+    );
+
+    let authorization = oidc
+        .login(redirect_uri, Some(String::from(device_id)))?
+        .build()
+        .await?;
+    println!(
+        "Open this URL in a browser to log in:\n{}",
+        authorization.url
+    );
+    let code = wait_for_redirect(listener).await?;
+    oidc.finish_authorization(code).await?;
+    oidc.finish_login().await?;
+    Ok(())
+}
+
+// The answer of the device authorization endpoint.
+#[derive(Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: Url,
+    verification_uri_complete: Option<Url>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Tokens {
+    access_token: String,
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenError {
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct Whoami {
+    user_id: OwnedUserId,
+}
+
+// Log in by approving a code on another device, e.g. a phone, so that nothing has to be typed
+// or opened here.
+pub async fn login_on_other_device(
+    client: &Client,
+    issuer: String,
+    device_id: &str,
+) -> Result<(), Box<dyn Error>> {
+    let oidc = client.oidc();
+    let client_metadata = client_metadata(None, GrantType::DeviceCode)?;
+    let registration = oidc
+        .register_client(&issuer, client_metadata.clone(), None)
+        .await?;
+    let provider = oidc.given_provider_metadata(&issuer).await?;
+    let endpoint = provider
+        .device_authorization_endpoint
+        .clone()
+        .ok_or("The OIDC provider doesn't support logging in from another device")?;
+
+    let http = http_client()?;
+    let scope = format!(
+        "openid urn:matrix:org.matrix.msc2967.client:api:* urn:matrix:org.matrix.msc2967.client:device:{device_id}"
+    );
+    let response = http
+        .post(endpoint)
+        .form(&[
+            ("client_id", registration.client_id.as_str()),
+            ("scope", &scope),
+        ])
+        .send()
+        .await?
+        .error_for_status()?;
+    let authorization: DeviceAuthorization = serde_json::from_slice(&response.bytes().await?)?;
+    let uri = authorization
+        .verification_uri_complete
+        .as_ref()
+        .unwrap_or(&authorization.verification_uri);
+    let qr = QrCode::new(uri.as_str())?.render::<Dense1x2>().build();
+    println!("{qr}");
+    println!(
+        "Scan the code or open {} on another device to log in, with the code {}",
+        authorization.verification_uri, authorization.user_code
+    );
+
+    // Ask until the login is approved, denied or the code expires.
+    let expiry = Instant::now() + Duration::from_secs(authorization.expires_in);
+    let mut interval = Duration::from_secs(authorization.interval.unwrap_or(5));
+    let tokens: Tokens = loop {
+        tokio::time::sleep(interval).await;
+        if Instant::now() > expiry {
+            return Err("The login code expired".into());
+        }
+        let response = http
+            .post(provider.token_endpoint().clone())
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                ("device_code", &authorization.device_code),
+                ("client_id", &registration.client_id),
+            ])
+            .send()
+            .await?;
+        let success = response.status().is_success();
+        let body = response.bytes().await?;
+        if success {
+            break serde_json::from_slice(&body)?;
+        }
+        let error: TokenError = serde_json::from_slice(&body)?;
+        match error.error.as_str() {
+            "authorization_pending" => {}
+            "slow_down" => interval += Duration::from_secs(5),
+            error => return Err(format!("Login failed: {error}").into()),
+        }
+    };
+
+    // The session needs the user ID, which the tokens don't tell.
+    let whoami = http
+        .get(
+            client
+                .homeserver()
+                .join("_matrix/client/v3/account/whoami")?,
+        )
+        .bearer_auth(&tokens.access_token)
+        .send()
+        .await?
+        .error_for_status()?;
+    let whoami: Whoami = serde_json::from_slice(&whoami.bytes().await?)?;
+    let registration = OidcRegistration {
+        issuer,
+        client_id: registration.client_id,
+        client_metadata,
+    };
+    let meta = SessionMeta {
+        user_id: whoami.user_id,
+        device_id: OwnedDeviceId::from(device_id),
+    };
+    restore_session(
+        client,
+        &registration,
+        meta,
+        tokens.access_token,
+        tokens.refresh_token,
+    )
+    .await
+}
+
+pub async fn restore_session(
+    client: &Client,
+    registration: &OidcRegistration,
+    meta: SessionMeta,
+    access_token: String,
+    refresh_token: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let session = OidcSession {
+        credentials: ClientCredentials::None {
+            client_id: registration.client_id.clone(),
+        },
+        metadata: registration.client_metadata.clone(),
+        user: UserSession {
+            meta,
+            tokens: OidcSessionTokens {
+                access_token,
+                refresh_token,
+                latest_id_token: None,
+            },
+            issuer_info: AuthenticationServerInfo::new(registration.issuer.clone(), None),
+        },
+    };
+    client.oidc().restore_session(session).await?;
+    Ok(())
+}
@@ -0,0 +1,222 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// The queue for messages sent later with --delay or --send-at, or when the homeserver can't be
+// reached, like sendmail's deferred queue. A queued message keeps its arguments, input and a
+// copy of its attachments, and `flush` runs matrixmail with them again once it is due.
+
+use crate::time;
+use serde::Deserialize;
+use serde::Serialize;
+use std::env;
+use std::error::Error;
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    // Seconds since the epoch.
+    due: i64,
+    args: Vec<String>,
+    input: String,
+    #[serde(default)]
+    attachments: Vec<PathBuf>,
+}
+
+// Set for the matrixmail runs of flush, which fail instead of queueing the message again.
+const FLUSHING: &str = "MATRIXMAIL_FLUSHING";
+
+pub fn flushing() -> bool {
+    env::var_os(FLUSHING).is_some()
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+// A delay like 30s, 10m, 2h or 1d; plain numbers are seconds.
+pub fn parse_delay(s: &str) -> Result<i64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number: i64 = number.parse().map_err(|_| format!("invalid delay {s}"))?;
+    match unit {
+        "s" => Ok(number),
+        "m" => Ok(number * 60),
+        "h" => Ok(number * 60 * 60),
+        "d" => Ok(number * 24 * 60 * 60),
+        _ => Err(format!("invalid delay unit {unit}")),
+    }
+}
+
+// A local time like 2024-05-01 08:00 or a date, or @ followed by seconds since the epoch.
+pub fn parse_time(s: &str) -> Result<i64, String> {
+    if let Some(secs) = s.strip_prefix('@') {
+        return secs.parse().map_err(|_| format!("invalid time {s}"));
+    }
+    [
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d",
+    ]
+    .iter()
+    .find_map(|format| time::parse_local(s, format))
+    .ok_or(format!("invalid time {s}, expected YYYY-MM-DD HH:MM"))
+}
+
+// The arguments without the options that queued the message, so that flushing sends it, and
+// without the attachments, which are replaced by their copies.
+fn queued_args() -> Vec<String> {
+    let mut args = Vec::new();
+    let mut skip = false;
+    for arg in env::args().skip(1) {
+        if skip {
+            skip = false;
+        } else if arg == "--delay" || arg == "--send-at" || arg == "-a" {
+            skip = true;
+        } else if !arg.starts_with("--delay=")
+            && !arg.starts_with("--send-at=")
+            && !arg.starts_with("-a")
+        {
+            args.push(arg);
+        }
+    }
+    args
+}
+
+pub async fn park(
+    data_dir: &Path,
+    due: i64,
+    input: String,
+    attachments: &[PathBuf],
+) -> Result<(), Box<dyn Error>> {
+    let queue_dir = data_dir.join("queue");
+    fs::create_dir_all(&queue_dir).await?;
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos();
+    let name = format!("{due}-{}-{nanos}", std::process::id());
+    // The files may have changed or be gone by the time the message is sent.
+    let mut copies = Vec::new();
+    if !attachments.is_empty() {
+        let attachments_dir = queue_dir.join("attachments").join(&name);
+        fs::create_dir_all(&attachments_dir).await?;
+        for (i, attachment) in attachments.iter().enumerate() {
+            let file_name = attachment.file_name().ok_or("Invalid attachment")?;
+            let copy = attachments_dir.join(i.to_string()).join(file_name);
+            fs::create_dir_all(copy.parent().unwrap()).await?;
+            fs::copy(attachment, &copy).await?;
+            copies.push(copy);
+        }
+    }
+    let entry = Entry {
+        due,
+        args: queued_args(),
+        input,
+        attachments: copies,
+    };
+    fs::write(queue_dir.join(&name), serde_json::to_vec(&entry)?).await?;
+    println!(
+        "Queued until {}.",
+        time::format_local(due, "%Y-%m-%d %H:%M:%S")
+    );
+    Ok(())
+}
+
+// Send the due messages by running matrixmail in send mode for each. Failed messages stay in
+// the queue for the next flush.
+pub async fn flush(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let queue_dir = data_dir.join("queue");
+    let mut entries = match fs::read_dir(&queue_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut failed = 0;
+    while let Some(file) = entries.next_entry().await? {
+        if !file.file_type().await?.is_file() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_slice(&fs::read(file.path()).await?)?;
+        if entry.due > now() {
+            continue;
+        }
+        let mut command = Command::new(env::current_exe()?);
+        command.arg0("mail").env(FLUSHING, "1");
+        for attachment in &entry.attachments {
+            command.arg("-a").arg(attachment);
+        }
+        let mut child = command.args(&entry.args).stdin(Stdio::piped()).spawn()?;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(entry.input.as_bytes())?;
+        if !child.wait()?.success() {
+            failed += 1;
+            continue;
+        }
+        fs::remove_file(file.path()).await?;
+        if !entry.attachments.is_empty() {
+            let attachments_dir = queue_dir.join("attachments").join(file.file_name());
+            fs::remove_dir_all(attachments_dir).await?;
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{failed} queued messages failed, they stay queued").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_units() {
+        assert_eq!(parse_delay("30"), Ok(30));
+        assert_eq!(parse_delay("30s"), Ok(30));
+        assert_eq!(parse_delay("10m"), Ok(600));
+        assert_eq!(parse_delay("2h"), Ok(7200));
+        assert_eq!(parse_delay("1d"), Ok(86400));
+        assert!(parse_delay("1w").is_err());
+        assert!(parse_delay("m").is_err());
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("@1714550400"), Ok(1714550400));
+        assert_eq!(parse_time("2024-05-01"), parse_time("2024-05-01 00:00"));
+        assert_eq!(
+            parse_time("2024-05-01T08:00:30"),
+            parse_time("2024-05-01 08:00").map(|time| time + 30)
+        );
+        assert!(parse_time("tomorrow").is_err());
+    }
+}
@@ -0,0 +1,119 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Waiting for read receipts, to know that someone has seen the message and not just that it was
+// delivered. A receipt on a later event counts as well, so the events after ours are collected
+// from the syncs.
+
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::ruma::events::receipt::ReceiptThread;
+use matrix_sdk::ruma::events::receipt::ReceiptType;
+use matrix_sdk::ruma::events::AnySyncEphemeralRoomEvent;
+use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::Client;
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::Duration;
+use std::time::Instant;
+
+// The last message sent to a room, and the events after it.
+struct Sent {
+    room_id: OwnedRoomId,
+    events: HashSet<OwnedEventId>,
+}
+
+// Whether someone else already read the event, before we started waiting.
+async fn read_already(client: &Client, room_id: &OwnedRoomId, event_id: &OwnedEventId) -> bool {
+    let Some(room) = client.get_room(room_id) else {
+        return false;
+    };
+    for thread in [ReceiptThread::Unthreaded, ReceiptThread::Main] {
+        if let Ok(receipts) = room
+            .load_event_receipts(ReceiptType::Read, thread, event_id)
+            .await
+        {
+            if receipts
+                .iter()
+                .any(|(user_id, _)| Some(&**user_id) != client.user_id())
+            {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+// Keep syncing until someone else has read each of the sent messages. Returns false if that
+// didn't happen before the timeout.
+pub async fn wait_read(
+    client: &Client,
+    sent: &[(OwnedRoomId, OwnedEventId)],
+    mut settings: SyncSettings,
+    sync_token: &mut Option<String>,
+    timeout: Duration,
+) -> Result<bool, Box<dyn Error>> {
+    let deadline = Instant::now() + timeout;
+    let mut unread = Vec::new();
+    for (room_id, event_id) in sent {
+        if !read_already(client, room_id, event_id).await {
+            unread.push(Sent {
+                room_id: room_id.clone(),
+                events: HashSet::from([event_id.clone()]),
+            });
+        }
+    }
+    while !unread.is_empty() {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        let response = client
+            .sync_once(settings.clone().timeout(deadline - now))
+            .await?;
+        settings = settings.token(response.next_batch.clone());
+        *sync_token = Some(response.next_batch);
+        unread.retain_mut(|sent| {
+            let Some(update) = response.rooms.join.get(&sent.room_id) else {
+                return true;
+            };
+            sent.events.extend(
+                update
+                    .timeline
+                    .events
+                    .iter()
+                    .filter_map(|event| event.event_id()),
+            );
+            let read = update.ephemeral.iter().any(|event| {
+                let Ok(AnySyncEphemeralRoomEvent::Receipt(receipt)) = event.deserialize() else {
+                    return false;
+                };
+                receipt.content.iter().any(|(event_id, receipts)| {
+                    sent.events.contains(event_id)
+                        && receipts.get(&ReceiptType::Read).is_some_and(|users| {
+                            users
+                                .keys()
+                                .any(|user_id| Some(&**user_id) != client.user_id())
+                        })
+                })
+            });
+            !read
+        });
+    }
+    Ok(true)
+}
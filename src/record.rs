@@ -0,0 +1,105 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// A local record of the sent messages in an mbox file, like mailx's record variable and -F,
+// and the mbox format for exports.
+
+use crate::address::Address;
+use crate::gethostname;
+use crate::queue;
+use crate::time;
+use std::error::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+pub struct Record<'a> {
+    pub to: &'a [Address],
+    pub cc: &'a [Address],
+    pub bcc: &'a [Address],
+    pub subject: Option<&'a str>,
+    pub body: &'a str,
+}
+
+// For -F, named after the first recipient, like the login name of an email address.
+pub fn file_name(address: &Address) -> PathBuf {
+    let address = address.to_string();
+    let name = address[1..].split(':').next().unwrap_or_default();
+    PathBuf::from(name)
+}
+
+fn list(addresses: &[Address]) -> String {
+    let addresses: Vec<String> = addresses.iter().map(Address::to_string).collect();
+    addresses.join(", ")
+}
+
+// A message as RFC 5322 text, sent at the time.
+pub fn message(time: i64, headers: &[(&str, String)], body: &str) -> String {
+    let mut message = format!(
+        "Date: {}\n",
+        time::format_local(time, "%a, %d %b %Y %H:%M:%S %z")
+    );
+    for (name, value) in headers {
+        message.push_str(&format!("{name}: {value}\n"));
+    }
+    message.push('\n');
+    message.push_str(body);
+    message.push('\n');
+    message
+}
+
+// The message in an mbox file, from the sender (an email address) at the time.
+pub fn entry(sender: &str, time: i64, message: &str) -> String {
+    let mut mbox = format!(
+        "From {sender} {}\n",
+        time::format_local(time, "%a %b %e %H:%M:%S %Y")
+    );
+    // Lines that would start the next message are quoted (mboxrd).
+    for line in message.lines() {
+        if line.trim_start_matches('>').starts_with("From ") {
+            mbox.push('>');
+        }
+        mbox.push_str(line);
+        mbox.push('\n');
+    }
+    mbox.push('\n');
+    mbox
+}
+
+pub async fn append(file: &Path, record: &Record<'_>) -> Result<(), Box<dyn Error>> {
+    let mut headers = Vec::new();
+    for (name, addresses) in [("To", record.to), ("Cc", record.cc), ("Bcc", record.bcc)] {
+        if !addresses.is_empty() {
+            headers.push((name, list(addresses)));
+        }
+    }
+    if let Some(subject) = record.subject {
+        headers.push(("Subject", String::from(subject)));
+    }
+    let sender = format!("matrixmail@{}", gethostname()?);
+    let now = queue::now();
+    let mbox = entry(&sender, now, &message(now, &headers, record.body));
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .await?;
+    f.write_all(mbox.as_bytes()).await?;
+    Ok(())
+}
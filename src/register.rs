@@ -0,0 +1,136 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Account registration with user-interactive authentication.
+// Stages without special handling are completed with the fallback web page.
+
+use crate::getpass;
+use matrix_sdk::matrix_auth::MatrixSession;
+use matrix_sdk::matrix_auth::MatrixSessionTokens;
+use matrix_sdk::ruma::api::client::account::register;
+use matrix_sdk::ruma::api::client::uiaa;
+use matrix_sdk::ruma::api::client::uiaa::AuthData;
+use matrix_sdk::ruma::api::client::uiaa::AuthType;
+use matrix_sdk::ruma::api::client::uiaa::UiaaInfo;
+use matrix_sdk::Client;
+use matrix_sdk::SessionMeta;
+use std::error::Error;
+use std::io;
+
+// Authentication for the next stage of the first flow that can be completed.
+fn next_stage(
+    client: &Client,
+    info: &UiaaInfo,
+    registration_token: Option<&str>,
+) -> Result<AuthData, Box<dyn Error>> {
+    let session = info.session.clone().ok_or("Registration is not possible")?;
+    let flow = info
+        .flows
+        .iter()
+        .find(|flow| {
+            registration_token.is_some() || !flow.stages.contains(&AuthType::RegistrationToken)
+        })
+        .ok_or("Registration requires a token, pass it with --registration-token")?;
+    let stage = flow
+        .stages
+        .iter()
+        .find(|stage| !info.completed.contains(stage))
+        .ok_or("Registration is not possible")?;
+    let auth = match stage {
+        AuthType::Dummy => {
+            let mut dummy = uiaa::Dummy::new();
+            dummy.session = Some(session);
+            AuthData::Dummy(dummy)
+        }
+        AuthType::RegistrationToken => {
+            let mut token = uiaa::RegistrationToken::new(String::from(registration_token.unwrap()));
+            token.session = Some(session);
+            AuthData::RegistrationToken(token)
+        }
+        // E.g. m.login.recaptcha or m.login.terms.
+        stage => {
+            let url = client.homeserver().join(&format!(
+                "_matrix/client/v3/auth/{stage}/fallback/web?session={session}"
+            ))?;
+            println!("Complete this step in a browser, then press enter:\n{url}");
+            io::stdin().read_line(&mut String::new())?;
+            AuthData::FallbackAcknowledgement(uiaa::FallbackAcknowledgement::new(session))
+        }
+    };
+    Ok(auth)
+}
+
+pub async fn register(
+    client: &Client,
+    user: &str,
+    password: &str,
+    device_name: &str,
+    display_name: &str,
+    registration_token: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let mut request = register::v3::Request::new();
+    request.username = Some(String::from(user));
+    request.password = Some(String::from(password));
+    request.device_id = Some(device_name.into());
+    request.initial_device_display_name = Some(String::from(display_name));
+    request.refresh_token = true;
+    // The number of stages completed so far, which has to grow with each attempt, or the same
+    // stage would be tried again forever, e.g. with a wrong token.
+    let mut completed = None;
+    let response = loop {
+        match client.matrix_auth().register(request.clone()).await {
+            Ok(response) => break response,
+            Err(e) => {
+                let Some(info) = e.as_uiaa_response() else {
+                    return Err(e.into());
+                };
+                if let Some(error) = &info.auth_error {
+                    return Err(format!("Registration failed: {}", error.message).into());
+                }
+                if completed.is_some_and(|completed| info.completed.len() <= completed) {
+                    return Err("Registration failed: the step was not completed".into());
+                }
+                completed = Some(info.completed.len());
+                request.auth = Some(next_stage(client, info, registration_token)?);
+            }
+        }
+    };
+    if client.matrix_auth().session().is_none() {
+        let auth_session = MatrixSession {
+            meta: SessionMeta {
+                user_id: response.user_id,
+                device_id: response.device_id.ok_or("No device ID in response")?,
+            },
+            tokens: MatrixSessionTokens {
+                access_token: response.access_token.ok_or("No access token in response")?,
+                refresh_token: response.refresh_token,
+            },
+        };
+        client.restore_session(auth_session).await?;
+    }
+    Ok(())
+}
+
+// The password for the new account, asked twice on the terminal.
+pub fn new_password() -> Result<String, Box<dyn Error>> {
+    let password = getpass("Password: ")?;
+    if getpass("Repeat password: ")? != password {
+        return Err("Passwords do not match".into());
+    }
+    Ok(password)
+}
@@ -0,0 +1,103 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// The joined rooms, to find the addresses to send to, and the details of a room, to find out
+// why sending to it fails.
+
+use clap::ValueEnum;
+use matrix_sdk::ruma::events::MessageLikeEventType;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use matrix_sdk::RoomState;
+use std::error::Error;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// Room ID, alias, name, members and encryption, separated by tabs
+    #[default]
+    Text,
+    /// A JSON object per room
+    Json,
+}
+
+pub async fn list(client: &Client, format: Format) -> Result<(), Box<dyn Error>> {
+    let mut rooms = Vec::new();
+    for room in client.joined_rooms() {
+        let name = room.display_name().await?.to_string();
+        rooms.push((room.is_encrypted().await?, name, room));
+    }
+    rooms.sort_by_key(|(_, name, _)| name.to_lowercase());
+    for (encrypted, name, room) in rooms {
+        let alias = room.canonical_alias();
+        let members = room.joined_members_count();
+        match format {
+            Format::Text => println!(
+                "{}\t{}\t{name}\t{members}\t{}",
+                room.room_id(),
+                alias.as_ref().map_or("-", |alias| alias.as_str()),
+                if encrypted { "encrypted" } else { "-" }
+            ),
+            Format::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "room_id": room.room_id(),
+                    "alias": alias,
+                    "name": name,
+                    "members": members,
+                    "encrypted": encrypted,
+                })
+            ),
+        }
+    }
+    Ok(())
+}
+
+pub async fn info(client: &Client, room: &Room) -> Result<(), Box<dyn Error>> {
+    println!("Room ID: {}", room.room_id());
+    if let Some(alias) = room.canonical_alias() {
+        println!("Alias: {alias}");
+    }
+    println!("Name: {}", room.display_name().await?);
+    if let Some(topic) = room.topic() {
+        println!("Topic: {topic}");
+    }
+    let membership = match room.state() {
+        RoomState::Joined => "joined",
+        RoomState::Invited => "invited",
+        RoomState::Left => "left",
+    };
+    println!("Membership: {membership}");
+    println!("Join rule: {}", room.join_rule().as_str());
+    let encryption = match room.is_encrypted().await? {
+        true => "on",
+        false => "off",
+    };
+    println!("Encryption: {encryption}");
+    println!("Members: {}", room.joined_members_count());
+    // Messages sent to an upgraded room end up in the old one, which nobody reads anymore.
+    if let Some(tombstone) = room.tombstone() {
+        println!("Replaced by: {}", tombstone.replacement_room);
+    }
+    let member = room.get_member(client.user_id().unwrap()).await?;
+    let power_level = member.as_ref().map_or(0, |member| member.power_level());
+    println!("Power level: {power_level}");
+    let can_send = room.state() == RoomState::Joined
+        && member.is_some_and(|member| member.can_send_message(MessageLikeEventType::RoomMessage));
+    println!("Can send: {}", if can_send { "yes" } else { "no" });
+    Ok(())
+}
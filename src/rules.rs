@@ -0,0 +1,133 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Filter rules for incoming messages, like procmail recipes: a YAML list of rules, each with
+// regular expressions for the room (name or ID), the sender and the body, and an action, to
+// deliver into a folder, drop the message or pipe it to a command. The first rule whose
+// expressions all match decides; messages without a matching rule go to the room's folder.
+//
+//   - sender: '^@alertmanager:'
+//     body: 'RESOLVED'
+//     drop: true
+//   - room: 'ops|infra'
+//     folder: alerts
+//   - body: '(?i)urgent'
+//     pipe: 'notify-send Matrix'
+
+use regex::Regex;
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    room: Option<String>,
+    sender: Option<String>,
+    body: Option<String>,
+    folder: Option<String>,
+    #[serde(default)]
+    drop: bool,
+    pipe: Option<String>,
+}
+
+pub enum Action {
+    Folder(String),
+    Drop,
+    // A shell command, which gets the message as mail on stdin.
+    Pipe(String),
+}
+
+pub struct Rule {
+    room: Option<Regex>,
+    sender: Option<Regex>,
+    body: Option<Regex>,
+    action: Action,
+}
+
+fn regex(pattern: Option<String>) -> Result<Option<Regex>, regex::Error> {
+    pattern.map(|pattern| Regex::new(&pattern)).transpose()
+}
+
+pub async fn load(file: &Path) -> Result<Vec<Rule>, Box<dyn Error>> {
+    let rule_files: Vec<RuleFile> = serde_yaml::from_str(&fs::read_to_string(file).await?)?;
+    let mut rules = Vec::new();
+    for rule in rule_files {
+        let action = match (rule.folder, rule.drop, rule.pipe) {
+            (Some(folder), false, None) => Action::Folder(folder),
+            (None, true, None) => Action::Drop,
+            (None, false, Some(command)) => Action::Pipe(command),
+            _ => return Err("Every rule needs one of folder, drop or pipe".into()),
+        };
+        rules.push(Rule {
+            room: regex(rule.room)?,
+            sender: regex(rule.sender)?,
+            body: regex(rule.body)?,
+            action,
+        });
+    }
+    Ok(rules)
+}
+
+fn matches(regex: &Option<Regex>, values: &[&str]) -> bool {
+    match regex {
+        Some(regex) => values.iter().any(|value| regex.is_match(value)),
+        None => true,
+    }
+}
+
+// The action of the first matching rule.
+pub fn action<'a>(
+    rules: &'a [Rule],
+    room_id: &str,
+    room_name: &str,
+    sender: &str,
+    body: &str,
+) -> Option<&'a Action> {
+    rules
+        .iter()
+        .find(|rule| {
+            matches(&rule.room, &[room_id, room_name])
+                && matches(&rule.sender, &[sender])
+                && matches(&rule.body, &[body])
+        })
+        .map(|rule| &rule.action)
+}
+
+pub async fn pipe(command: &str, text: &str) -> Result<(), Box<dyn Error>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(text.as_bytes())
+        .await?;
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(format!("{command} failed with {status}").into());
+    }
+    Ok(())
+}
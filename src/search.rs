@@ -0,0 +1,202 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Searching rooms for messages, with the homeserver's search API where it can, and by paging
+// through the history for encrypted rooms, whose messages the homeserver can't read, and for
+// homeservers without search. Matches are printed a line each, tab-separated, for grep and cut.
+
+use crate::time;
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::ruma::api::client::filter::RoomEventFilter;
+use matrix_sdk::ruma::api::client::search::search_events::v3::Categories;
+use matrix_sdk::ruma::api::client::search::search_events::v3::Criteria;
+use matrix_sdk::ruma::api::client::search::search_events::v3::OrderBy;
+use matrix_sdk::ruma::api::client::search::search_events::v3::Request;
+use matrix_sdk::ruma::events::AnyMessageLikeEvent;
+use matrix_sdk::ruma::events::AnyTimelineEvent;
+use matrix_sdk::ruma::events::MessageLikeEvent;
+use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use std::error::Error;
+
+// How many events to ask for at once when paging through the history.
+const PAGE_SIZE: u32 = 100;
+
+struct Found {
+    // Seconds since the epoch.
+    time: i64,
+    room_id: OwnedRoomId,
+    event_id: OwnedEventId,
+    sender: OwnedUserId,
+    body: String,
+}
+
+fn message(event: AnyTimelineEvent) -> Option<Found> {
+    let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+        MessageLikeEvent::Original(message),
+    )) = event
+    else {
+        return None;
+    };
+    Some(Found {
+        time: u64::from(message.origin_server_ts.as_secs()) as i64,
+        room_id: message.room_id,
+        event_id: message.event_id,
+        sender: message.sender,
+        body: String::from(message.content.body()),
+    })
+}
+
+// The lines of the message with the pattern, ignoring case.
+fn matching_lines<'a>(body: &'a str, pattern: &str) -> Vec<&'a str> {
+    let pattern = pattern.to_lowercase();
+    body.lines()
+        .filter(|line| line.to_lowercase().contains(&pattern))
+        .collect()
+}
+
+// The server's search, newest first, up to the time.
+async fn server(
+    client: &Client,
+    rooms: &[Room],
+    pattern: &str,
+    since: Option<i64>,
+) -> Result<Vec<Found>, matrix_sdk::HttpError> {
+    let mut filter = RoomEventFilter::default();
+    filter.rooms = Some(rooms.iter().map(|room| room.room_id().to_owned()).collect());
+    let mut criteria = Criteria::new(String::from(pattern));
+    criteria.filter = filter;
+    criteria.order_by = Some(OrderBy::Recent);
+    let mut found = Vec::new();
+    let mut next_batch = None;
+    loop {
+        let mut categories = Categories::new();
+        categories.room_events = Some(criteria.clone());
+        let mut request = Request::new(categories);
+        request.next_batch = next_batch;
+        let response = client.send(request, None).await?;
+        let results = response.search_categories.room_events;
+        for result in results.results {
+            let Some(Ok(event)) = result.result.map(|event| event.deserialize()) else {
+                continue;
+            };
+            let Some(message) = message(event) else {
+                continue;
+            };
+            if since.is_some_and(|since| message.time < since) {
+                return Ok(found);
+            }
+            found.push(message);
+        }
+        match results.next_batch {
+            Some(batch) => next_batch = Some(batch),
+            None => return Ok(found),
+        }
+    }
+}
+
+// Page back through the room's messages up to the time, which are decrypted on the way.
+async fn history(
+    room: &Room,
+    pattern: &str,
+    since: Option<i64>,
+) -> Result<Vec<Found>, Box<dyn Error>> {
+    let mut found = Vec::new();
+    let mut options = MessagesOptions::backward();
+    loop {
+        options.limit = UInt::from(PAGE_SIZE);
+        let messages = room.messages(options).await?;
+        for event in messages.chunk {
+            let Ok(event) = event.event.deserialize() else {
+                continue;
+            };
+            let time = u64::from(event.origin_server_ts().as_secs()) as i64;
+            if since.is_some_and(|since| time < since) {
+                return Ok(found);
+            }
+            if let Some(message) = message(event) {
+                if !matching_lines(&message.body, pattern).is_empty() {
+                    found.push(message);
+                }
+            }
+        }
+        // The start of the room.
+        let Some(end) = messages.end else {
+            return Ok(found);
+        };
+        options = MessagesOptions::backward();
+        options.from = Some(end);
+    }
+}
+
+// Print the messages with the pattern, oldest first, and whether there were any.
+pub async fn search(
+    client: &Client,
+    rooms: Vec<Room>,
+    pattern: &str,
+    since: Option<i64>,
+) -> Result<bool, Box<dyn Error>> {
+    let mut encrypted = Vec::new();
+    let mut plain = Vec::new();
+    for room in rooms {
+        if room.is_encrypted().await? {
+            encrypted.push(room);
+        } else {
+            plain.push(room);
+        }
+    }
+    let mut found = Vec::new();
+    if !plain.is_empty() {
+        match server(client, &plain, pattern, since).await {
+            Ok(messages) => found.extend(messages),
+            // Not every homeserver implements search.
+            Err(e) => {
+                eprintln!("Server search failed, searching the history instead: {e}");
+                encrypted.append(&mut plain);
+            }
+        }
+    }
+    for room in &encrypted {
+        found.extend(history(room, pattern, since).await?);
+    }
+    found.sort_by_key(|message| message.time);
+    for message in &found {
+        let room_name = match client.get_room(&message.room_id) {
+            Some(room) => room.display_name().await?.to_string(),
+            None => message.room_id.to_string(),
+        };
+        // The server also matches other forms of the words.
+        let mut lines = matching_lines(&message.body, pattern);
+        if lines.is_empty() {
+            lines.extend(message.body.lines().next());
+        }
+        for line in lines {
+            println!(
+                "{}\t{room_name}\t{}\t{}\t{line}",
+                time::format_local(message.time, "%Y-%m-%d %H:%M:%S"),
+                message.sender,
+                message.event_id
+            );
+        }
+    }
+    Ok(!found.is_empty())
+}
@@ -0,0 +1,85 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Small JSON files in the data directory, like the alias cache and the pinned keys. Messages to
+// several rooms are sent at the same time, so each file has a lock for reading and updating it,
+// and is replaced in one go, so that no one sees it half written.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use tokio::fs;
+use tokio::sync::Mutex;
+use tokio::sync::OwnedMutexGuard;
+
+static LOCKS: OnceLock<std::sync::Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = OnceLock::new();
+
+async fn lock(file: &Path) -> OwnedMutexGuard<()> {
+    let lock = LOCKS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(file.to_path_buf())
+        .or_default()
+        .clone();
+    lock.lock_owned().await
+}
+
+async fn load<T: DeserializeOwned + Default>(file: &Path) -> Result<T, Box<dyn Error>> {
+    match fs::read(file).await {
+        Ok(buffer) => {
+            Ok(serde_json::from_slice(&buffer).map_err(|e| format!("{}: {e}", file.display()))?)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Written next to the file and renamed over it.
+async fn save<T: Serialize>(file: &Path, value: &T) -> Result<(), Box<dyn Error>> {
+    let mut tmp = file.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    fs::write(&tmp, serde_json::to_vec(value)?).await?;
+    fs::rename(&tmp, file).await?;
+    Ok(())
+}
+
+pub async fn read<T: DeserializeOwned + Default>(file: &Path) -> Result<T, Box<dyn Error>> {
+    let _lock = lock(file).await;
+    load(file).await
+}
+
+// Change what is in the file now, not what was read before, which may be outdated.
+pub async fn update<T, R>(
+    file: &Path,
+    change: impl FnOnce(&mut T) -> R,
+) -> Result<R, Box<dyn Error>>
+where
+    T: DeserializeOwned + Serialize + Default,
+{
+    let _lock = lock(file).await;
+    let mut value = load(file).await?;
+    let result = change(&mut value);
+    save(file, &value).await?;
+    Ok(result)
+}
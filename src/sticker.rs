@@ -0,0 +1,128 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Stickers sent with --sticker, as mxc: URI of an uploaded image or as image file to upload.
+
+use crate::attachment;
+use matrix_sdk::ruma::events::room::message::ImageMessageEventContent;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::room::ImageInfo;
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::sticker::StickerEventContent;
+use matrix_sdk::ruma::OwnedMxcUri;
+use matrix_sdk::Client;
+use std::convert::Infallible;
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::sync::OnceCell;
+
+#[derive(Clone, Debug)]
+pub enum Sticker {
+    Uri(OwnedMxcUri),
+    File(PathBuf),
+}
+
+impl FromStr for Sticker {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.starts_with("mxc://") {
+            true => Sticker::Uri(s.into()),
+            false => Sticker::File(PathBuf::from(s)),
+        })
+    }
+}
+
+// What is sent for a sticker. Stickers can't be encrypted, so in encrypted rooms an image file
+// is sent as image message instead, with the image encrypted like attachments.
+#[derive(Clone)]
+pub enum Content {
+    Sticker(StickerEventContent),
+    Image(RoomMessageEventContent),
+}
+
+// The message is the description of the sticker.
+async fn content(
+    client: &Client,
+    sticker: &Sticker,
+    description: &str,
+    encrypted: bool,
+) -> Result<Content, Box<dyn Error>> {
+    let description = String::from(match description {
+        "" => "sticker",
+        description => description,
+    });
+    Ok(match sticker {
+        // Uploaded unencrypted already.
+        Sticker::Uri(uri) => Content::Sticker(StickerEventContent::new(
+            description,
+            ImageInfo::new(),
+            uri.clone(),
+        )),
+        Sticker::File(path) => {
+            let image = attachment::load(path).await?;
+            let info = attachment::image_info(&image);
+            match attachment::upload_source(client, &image, encrypted).await? {
+                MediaSource::Plain(uri) => {
+                    Content::Sticker(StickerEventContent::new(description, info, uri))
+                }
+                source => {
+                    let image = ImageMessageEventContent::new(description, source);
+                    Content::Image(RoomMessageEventContent::new(MessageType::Image(
+                        image.info(Box::new(info)),
+                    )))
+                }
+            }
+        }
+    })
+}
+
+// The sticker of a mail, with the image file uploaded once for the unencrypted rooms and once,
+// encrypted, for the encrypted ones, when first sent to one.
+pub struct Upload {
+    sticker: Sticker,
+    description: String,
+    plain: OnceCell<Content>,
+    encrypted: OnceCell<Content>,
+}
+
+impl Upload {
+    pub fn new(sticker: Sticker, description: String) -> Upload {
+        Upload {
+            sticker,
+            description,
+            plain: OnceCell::new(),
+            encrypted: OnceCell::new(),
+        }
+    }
+
+    pub async fn content(
+        &self,
+        client: &Client,
+        encrypted: bool,
+    ) -> Result<&Content, Box<dyn Error>> {
+        let cell = match encrypted {
+            true => &self.encrypted,
+            false => &self.plain,
+        };
+        cell.get_or_try_init(|| content(client, &self.sticker, &self.description, encrypted))
+            .await
+    }
+}
@@ -0,0 +1,106 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Exit statuses from sysexits.h, like sendmail's, so that an MTA or a cron wrapper can tell a
+// message worth trying again later from one that will never get through.
+
+use crate::address::Unresolvable;
+use crate::unreachable;
+use matrix_sdk::ruma::api::client::error::ErrorBody;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+
+// The address doesn't lead to a room.
+pub const EX_NOUSER: i32 = 67;
+// The homeserver refused the login or the session, or there is none.
+pub const EX_UNAVAILABLE: i32 = 69;
+// Worth trying again later: the homeserver can't be reached, is rate limiting or failing.
+pub const EX_TEMPFAIL: i32 = 75;
+// The session is no longer valid.
+pub const EX_NOPERM: i32 = 77;
+
+// An error with the exit status it causes.
+#[derive(Debug)]
+pub struct Failure {
+    status: i32,
+    message: String,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Failure {}
+
+pub fn fail(status: i32, message: impl Display) -> Box<dyn Error> {
+    Box::new(Failure {
+        status,
+        message: message.to_string(),
+    })
+}
+
+// The error with what failed, keeping the exit status it causes.
+pub fn context(error: Box<dyn Error>, context: impl Display) -> Box<dyn Error> {
+    fail(status(&*error), format_args!("{context}: {error}"))
+}
+
+fn api_error<'a>(
+    error: &'a (dyn Error + 'static),
+) -> Option<&'a matrix_sdk::ruma::api::client::Error> {
+    match error.downcast_ref::<matrix_sdk::Error>() {
+        Some(error) => error.as_client_api_error(),
+        None => error
+            .downcast_ref::<matrix_sdk::HttpError>()?
+            .as_client_api_error(),
+    }
+}
+
+// Errors that aren't classified exit with 1, like before.
+pub fn status(error: &(dyn Error + 'static)) -> i32 {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        return failure.status;
+    }
+    if error.is::<Unresolvable>() {
+        return EX_NOUSER;
+    }
+    if unreachable(error) {
+        return EX_TEMPFAIL;
+    }
+    let Some(api_error) = api_error(error) else {
+        return 1;
+    };
+    let kind = match &api_error.body {
+        ErrorBody::Standard { kind, .. } => Some(kind),
+        _ => None,
+    };
+    match kind {
+        Some(ErrorKind::LimitExceeded { .. }) => EX_TEMPFAIL,
+        Some(
+            ErrorKind::Forbidden
+            | ErrorKind::UnknownToken { .. }
+            | ErrorKind::MissingToken
+            | ErrorKind::UserDeactivated,
+        ) => EX_UNAVAILABLE,
+        _ if api_error.status_code.is_server_error() => EX_TEMPFAIL,
+        _ => 1,
+    }
+}
@@ -0,0 +1,73 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// New messages printed as they arrive, like tail -f, as text or as JSON lines for other tools.
+
+use crate::time;
+use clap::ValueEnum;
+use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// Time, room, sender and message
+    #[default]
+    Text,
+    /// A JSON object per message
+    Json,
+}
+
+// Print the messages of every following sync in the rooms, or in all rooms without any.
+pub fn watch(client: &Client, room_ids: Vec<OwnedRoomId>, format: Format) {
+    client.add_event_handler(move |event: OriginalSyncRoomMessageEvent, room: Room| {
+        let watched =
+            room_ids.is_empty() || room_ids.iter().any(|room_id| room_id == room.room_id());
+        async move {
+            if !watched {
+                return;
+            }
+            let room_name = match room.display_name().await {
+                Ok(name) => name.to_string(),
+                Err(_) => room.room_id().to_string(),
+            };
+            let secs = u64::from(event.origin_server_ts.as_secs()) as i64;
+            match format {
+                Format::Text => println!(
+                    "{} {room_name} {}: {}",
+                    time::format_local(secs, "%Y-%m-%d %H:%M:%S"),
+                    event.sender,
+                    // Continuation lines are indented, so that every message starts a line.
+                    event.content.body().replace('\n', "\n    ")
+                ),
+                Format::Json => println!(
+                    "{}",
+                    serde_json::json!({
+                        "room_id": room.room_id(),
+                        "room": room_name,
+                        "event_id": event.event_id,
+                        "sender": event.sender,
+                        "time": secs,
+                        "body": event.content.body(),
+                    })
+                ),
+            }
+        }
+    });
+}
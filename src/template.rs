@@ -0,0 +1,186 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Message templates, so that scripts can share a layout instead of building strings in shell.
+// Placeholders are {{subject}}, {{body}}, {{hostname}}, {{date}} and {{env.NAME}}, and
+// {{room}}, {{address}}, {{name}} and {{vars.NAME}}, which differ between recipients and are
+// replaced when sending. Messages without template are sent as they are.
+
+use crate::addressbook::Entry;
+use crate::gethostname;
+use crate::time;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+fn value(name: &str, subject: &str, body: &str) -> Result<String, Box<dyn Error>> {
+    match name {
+        "subject" => Ok(String::from(subject)),
+        "body" => Ok(String::from(body)),
+        "hostname" => Ok(gethostname()?),
+        "date" => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            Ok(time::format_local(now as i64, "%Y-%m-%d %H:%M:%S"))
+        }
+        // Unset variables are empty, like in the shell.
+        _ => match name.strip_prefix("env.") {
+            Some(variable) => Ok(env::var(variable).unwrap_or_default()),
+            None => Err(format!("Unknown template placeholder {{{{{name}}}}}").into()),
+        },
+    }
+}
+
+fn personal(name: &str) -> bool {
+    matches!(name, "room" | "address" | "name") || name.starts_with("vars.")
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Piece {
+    Text(String),
+    // The name, and the placeholder as written.
+    Personal(String, String),
+}
+
+// A message with the placeholders that differ between recipients still in it. Only the template
+// has them, not the body or values put into it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message(Vec<Piece>);
+
+// What the placeholders stand for at one recipient: the room, and the address book entry of
+// its address, if any.
+pub struct Recipient<'a> {
+    pub room: String,
+    pub address: String,
+    pub entry: Option<(&'a str, &'a Entry)>,
+}
+
+impl Recipient<'_> {
+    // Values missing from the entry are empty, like unset variables.
+    fn value(&self, name: &str) -> String {
+        match (name, self.entry) {
+            ("room", _) => self.room.clone(),
+            ("address", _) => self.address.clone(),
+            ("name", Some((entry_name, _))) => String::from(entry_name),
+            ("name", None) => self.address.clone(),
+            (_, entry) => name
+                .strip_prefix("vars.")
+                .zip(entry)
+                .and_then(|(variable, (_, entry))| entry.vars().get(variable).cloned())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Message {
+    // A message without template, the same for everyone.
+    pub fn text(text: &str) -> Message {
+        Message(vec![Piece::Text(String::from(text))])
+    }
+
+    // The same message after a line like the To: and Cc: header.
+    pub fn prefixed(&self, prefix: String) -> Message {
+        let mut pieces = vec![Piece::Text(prefix)];
+        pieces.extend(self.0.iter().cloned());
+        Message(pieces)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.to_string().is_empty()
+    }
+
+    fn fill(&self, value: impl Fn(&str) -> String) -> String {
+        self.0
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => text.clone(),
+                Piece::Personal(name, _) => value(name),
+            })
+            .collect()
+    }
+
+    pub fn personalize(&self, recipient: &Recipient) -> String {
+        self.fill(|name| recipient.value(name))
+    }
+}
+
+// The message as it is before being personalized, with the placeholders as written.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for piece in &self.0 {
+            match piece {
+                Piece::Text(text) | Piece::Personal(_, text) => f.write_str(text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Where the next placeholder starts and ends.
+fn placeholder(text: &str) -> Option<(usize, usize)> {
+    let start = text.find("{{")?;
+    let end = text[start..].find("}}")?;
+    Some((start, start + end + 2))
+}
+
+pub fn render(template: &str, subject: &str, body: &str) -> Result<Message, Box<dyn Error>> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let (_, end) = placeholder(rest).ok_or("Unterminated template placeholder")?;
+        let name = rest[start + 2..end - 2].trim();
+        pieces.push(Piece::Text(String::from(&rest[..start])));
+        pieces.push(match personal(name) {
+            true => Piece::Personal(String::from(name), String::from(&rest[start..end])),
+            false => Piece::Text(value(name, subject, body)?),
+        });
+        rest = &rest[end..];
+    }
+    pieces.push(Piece::Text(String::from(rest)));
+    Ok(Message(pieces))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recipient() -> Recipient<'static> {
+        Recipient {
+            room: String::from("Ops"),
+            address: String::from("#ops:example.org"),
+            entry: None,
+        }
+    }
+
+    #[test]
+    fn body_keeps_placeholders() {
+        let message = render("{{room}}: {{body}}", "", "{{room}}").unwrap();
+        assert_eq!(message.personalize(&recipient()), "Ops: {{room}}");
+    }
+
+    #[test]
+    fn without_template() {
+        let message = Message::text("Hello {{ room }}, {{other}} {{name}}");
+        assert_eq!(message.to_string(), "Hello {{ room }}, {{other}} {{name}}");
+        assert_eq!(
+            message.personalize(&recipient()),
+            "Hello {{ room }}, {{other}} {{name}}"
+        );
+    }
+}
@@ -0,0 +1,62 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Date formatting and parsing in local time, without pulling in a date library.
+
+use std::ffi::CString;
+
+// Format seconds since the epoch with strftime(3).
+pub fn format_local(secs: i64, format: &str) -> String {
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return secs.to_string();
+    }
+    let format = CString::new(format).unwrap();
+    let mut buffer = vec![0u8; 128];
+    let n = unsafe {
+        libc::strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            format.as_ptr(),
+            &tm,
+        )
+    };
+    buffer.truncate(n);
+    String::from_utf8_lossy(&buffer).into_owned()
+}
+
+// Parse a local time with strptime(3) into seconds since the epoch.
+pub fn parse_local(s: &str, format: &str) -> Option<i64> {
+    let s = CString::new(s).ok()?;
+    let format = CString::new(format).unwrap();
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let end = unsafe { libc::strptime(s.as_ptr(), format.as_ptr(), &mut tm) };
+    // All of the string has to match.
+    if end.is_null() || unsafe { *end } != 0 {
+        return None;
+    }
+    // Let mktime figure out daylight saving time.
+    tm.tm_isdst = -1;
+    match unsafe { libc::mktime(&mut tm) } {
+        -1 => None,
+        // time_t is narrower on some systems.
+        #[allow(clippy::unnecessary_cast)]
+        secs => Some(secs as i64),
+    }
+}
@@ -0,0 +1,129 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Which devices of the members of an encrypted room to trust with the message.
+// matrixmail encrypts to every device of every member; stricter policies refuse to send instead.
+
+use crate::state;
+use crate::sync_once;
+use clap::ValueEnum;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::ruma::api::client::filter::FilterDefinition;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use matrix_sdk::RoomMemberships;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Trust {
+    /// Encrypt to all devices
+    #[default]
+    All,
+    /// Refuse when a member's cross-signing identity changed since it was first seen
+    Tofu,
+    /// Refuse when a member has unverified devices
+    Verified,
+}
+
+// The first seen master key of each user, for TOFU.
+type PinnedKeys = HashMap<String, String>;
+
+pub async fn check(
+    client: &Client,
+    room: &Room,
+    trust: Trust,
+    data_dir: &Path,
+) -> Result<(), Box<dyn Error>> {
+    if trust == Trust::All || !room.is_encrypted().await? {
+        return Ok(());
+    }
+    // The devices and identities of members are only known once their keys were queried, which
+    // the SDK does before the next sync, so sync without any events to have it done.
+    let settings = SyncSettings::default().filter(FilterDefinition::ignore_all().into());
+    sync_once(client, &settings).await?;
+    let encryption = client.encryption();
+    let pinned_keys_file = data_dir.join("pinned-keys");
+    let pinned_keys: PinnedKeys = state::read(&pinned_keys_file).await?;
+    let mut new_keys = PinnedKeys::new();
+    let mut problems = Vec::new();
+    for member in room.members(RoomMemberships::ACTIVE).await? {
+        let user_id = member.user_id();
+        match trust {
+            Trust::All => {}
+            Trust::Tofu => {
+                // Users without cross-signing have nothing to pin.
+                let Some(identity) = encryption.get_user_identity(user_id).await? else {
+                    continue;
+                };
+                let Some(key) = identity.master_key().get_first_key() else {
+                    continue;
+                };
+                let key = key.to_base64();
+                match pinned_keys.get(user_id.as_str()) {
+                    Some(pinned_key) if *pinned_key != key => {
+                        problems.push(format!("{user_id} changed their identity"));
+                    }
+                    Some(_) => {}
+                    None => {
+                        new_keys.insert(user_id.to_string(), key);
+                    }
+                }
+            }
+            Trust::Verified => {
+                // Without keys there is nothing to verify, and the message couldn't be read.
+                let devices = encryption.get_user_devices(user_id).await?;
+                if devices.devices().next().is_none() {
+                    problems.push(format!("{user_id} has no known devices"));
+                    continue;
+                }
+                if encryption.get_user_identity(user_id).await?.is_none() {
+                    problems.push(format!("{user_id} has no cross-signing identity"));
+                    continue;
+                }
+                for device in devices.devices() {
+                    if client.device_id() != Some(device.device_id()) && !device.is_verified() {
+                        problems.push(format!(
+                            "{user_id} has unverified device {}",
+                            device.device_id()
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    // Another room may have pinned some of them in the meantime, the first one stays.
+    if !new_keys.is_empty() {
+        state::update(&pinned_keys_file, |pinned_keys: &mut PinnedKeys| {
+            for (user_id, key) in new_keys {
+                pinned_keys.entry(user_id).or_insert(key);
+            }
+        })
+        .await?;
+    }
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "Refusing to send to {}: {}",
+            room.room_id(),
+            problems.join(", ")
+        )
+        .into()),
+    }
+}
@@ -0,0 +1,139 @@
+/*
+matrixmail - POSIX mailx send mode over Matrix
+Copyright (C) 2022  Axel Pirek
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Interactive emoji (SAS) verification of this device against another one of the account.
+// The verification messages arrive as to-device events, so a sync runs in the background.
+
+use crate::prompt;
+use futures_util::StreamExt;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::encryption::verification::SasState;
+use matrix_sdk::encryption::verification::SasVerification;
+use matrix_sdk::encryption::verification::Verification;
+use matrix_sdk::encryption::verification::VerificationRequest;
+use matrix_sdk::encryption::verification::VerificationRequestState;
+use matrix_sdk::ruma::api::client::filter::FilterDefinition;
+use matrix_sdk::ruma::DeviceId;
+use matrix_sdk::Client;
+use std::error::Error;
+
+// Verify with the given device, or with whichever device of the account answers first.
+pub async fn verify(client: &Client, device_id: Option<&DeviceId>) -> Result<(), Box<dyn Error>> {
+    let user_id = client.user_id().unwrap();
+    let encryption = client.encryption();
+    // The first sync uploads our keys and fetches the other devices' keys.
+    let filter = FilterDefinition::with_lazy_loading();
+    let settings = SyncSettings::default().filter(filter.into());
+    let response = client.sync_once(settings.clone()).await?;
+    let settings = settings.token(response.next_batch);
+
+    let request = match device_id {
+        Some(device_id) => {
+            encryption
+                .get_device(user_id, device_id)
+                .await?
+                .ok_or(format!("Unknown device {device_id}"))?
+                .request_verification()
+                .await?
+        }
+        None => {
+            encryption
+                .get_user_identity(user_id)
+                .await?
+                .ok_or("The account has no cross-signing keys, give a device ID")?
+                .request_verification()
+                .await?
+        }
+    };
+    println!("Accept the verification request on the other device.");
+
+    let sync = tokio::spawn({
+        let client = client.clone();
+        async move { client.sync(settings).await }
+    });
+    let result = wait_for_sas(request).await;
+    sync.abort();
+    result
+}
+
+async fn wait_for_sas(request: VerificationRequest) -> Result<(), Box<dyn Error>> {
+    let mut changes = request.changes();
+    while let Some(state) = changes.next().await {
+        match state {
+            VerificationRequestState::Ready { .. } => {
+                let sas = request
+                    .start_sas()
+                    .await?
+                    .ok_or("The other device doesn't support emoji verification")?;
+                return compare_emojis(sas).await;
+            }
+            // The other device started the verification itself.
+            VerificationRequestState::Transitioned {
+                verification: Verification::SasV1(sas),
+            } => {
+                sas.accept().await?;
+                return compare_emojis(sas).await;
+            }
+            VerificationRequestState::Transitioned { .. } => {
+                return Err("The other device doesn't support emoji verification".into());
+            }
+            VerificationRequestState::Cancelled(info) => {
+                return Err(format!("Verification cancelled: {}", info.reason()).into());
+            }
+            _ => {}
+        }
+    }
+    Err("Verification request ended".into())
+}
+
+async fn compare_emojis(sas: SasVerification) -> Result<(), Box<dyn Error>> {
+    let mut changes = sas.changes();
+    while let Some(state) = changes.next().await {
+        match state {
+            SasState::KeysExchanged { emojis, decimals } => {
+                match emojis {
+                    Some(emojis) => {
+                        for emoji in emojis.emojis {
+                            println!("{}  {}", emoji.symbol, emoji.description);
+                        }
+                    }
+                    None => println!("{} {} {}", decimals.0, decimals.1, decimals.2),
+                }
+                // Reading the answer blocks, but the sync has to go on.
+                let answer = tokio::task::spawn_blocking(|| {
+                    prompt("Do they match the other device? [y/N] ")
+                })
+                .await??;
+                if !answer.trim().eq_ignore_ascii_case("y") {
+                    sas.mismatch().await?;
+                    return Err("Verification failed, the emojis don't match".into());
+                }
+                sas.confirm().await?;
+            }
+            SasState::Done { .. } => {
+                println!("Device verified.");
+                return Ok(());
+            }
+            SasState::Cancelled(info) => {
+                return Err(format!("Verification cancelled: {}", info.reason()).into());
+            }
+            _ => {}
+        }
+    }
+    Err("Verification ended".into())
+}